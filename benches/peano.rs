@@ -0,0 +1,26 @@
+#![feature(test)]
+
+extern crate test;
+
+use minilisp::Interpreter;
+use test::Bencher;
+
+fn bench_source(b: &mut Bencher, source: &str) {
+    let mut intp = Interpreter::new().init();
+    b.iter(|| intp.execute_source(source.to_owned()).expect("Error"));
+}
+
+#[bench]
+fn bench_p_mul_small(b: &mut Bencher) {
+    bench_source(b, "(p:mul p:3 p:4)");
+}
+
+#[bench]
+fn bench_p_mul_large(b: &mut Bencher) {
+    bench_source(b, "(p:mul p:9 p:9)");
+}
+
+#[bench]
+fn bench_q_length(b: &mut Bencher) {
+    bench_source(b, "(q:length '(a b c d e f g h i j k l m n o p))");
+}
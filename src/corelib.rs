@@ -1,6 +1,9 @@
-use super::{Interpreter, Value};
+use std::sync::Arc;
 
-pub const BUILTINS: [&str; 19] = [
+use super::module;
+use super::{Error, Interpreter, Scope, Value};
+
+pub const BUILTINS: [&str; 38] = [
     // Special items
     "error",
     "quote",
@@ -9,6 +12,7 @@ pub const BUILTINS: [&str; 19] = [
     "discard",
     "assert",
     "lambda",
+    "closure",
     "block",
     "branch",
     // Equality check
@@ -17,6 +21,27 @@ pub const BUILTINS: [&str; 19] = [
     // Namespace operators
     "set",
     "del",
+    // Modules
+    "import",
+    "export",
+    // Integer arithmetic
+    "i:add",
+    "i:sub",
+    "i:mul",
+    "i:div",
+    "i:rem",
+    "i:lt?",
+    "i:gt?",
+    "i:int?",
+    // Strings
+    "s:length",
+    "s:concat",
+    "s:substring",
+    "s:split",
+    "s:join",
+    "s:to_symbol",
+    "s:from_symbol",
+    "s:str?",
     // Operations on quoted expressions as lists
     "q:reverse",
     "q:concat",
@@ -31,88 +56,134 @@ pub const BUILTINS: [&str; 19] = [
 macro_rules! boolvalue {
     ($v:expr) => {{
         if $v {
-            Value::Idfr("true".to_owned())
+            // Same as `true` resolves to, so that it works without the prelude
+            Value::Quot(box Value::Idfr("true".to_owned()), None)
         } else {
             Value::Unit
         }
     }};
 }
 
+/// Both arguments of a binary integer operation
+fn int_args(name: &str, args: &[Value]) -> Result<(i64, i64), Error> {
+    if args.len() != 2 {
+        return Err(Error::arity(name, 2, args.len()));
+    }
+    match (&args[0], &args[1]) {
+        (Value::Int(a), Value::Int(b)) => Ok((*a, *b)),
+        _ => Err(Error::mismatch("Integer arguments required")),
+    }
+}
+
+/// Contents of a string argument
+fn str_arg(arg: &Value) -> Result<&str, Error> {
+    match arg {
+        Value::Str(s) => Ok(s),
+        _ => Err(Error::mismatch("String argument required")),
+    }
+}
+
 /// Outer options is None if the function is not found
 /// Inner result marks success of exection
 #[must_use]
-pub fn call(intp: &mut Interpreter, name: String, args: Vec<Value>) -> Result<Value, String> {
+pub fn call(intp: &mut Interpreter, name: String, args: Vec<Value>) -> Result<Value, Error> {
     match name.as_str() {
-        // evaluate quoted expression
+        // evaluate quoted expression, in the scope it was quoted in
         "unquote" => {
             if args.len() != 1 {
-                return Err("Arg count".to_owned());
+                return Err(Error::arity(&name, 1, args.len()));
             }
-            if let Value::Quot(e) = args[0].clone() {
+            if let Value::Quot(e, scope) = args[0].clone() {
+                intp.enter_scope(scope);
                 Ok(*e)
             } else {
-                Err(format!("Only quote can be unquoted, {:?} is invalid", args[0]))
+                Err(Error::TypeMismatch(format!(
+                    "Only quote can be unquoted, {} is invalid",
+                    args[0]
+                )))
             }
         },
         // discard an expression
         "discard" => Ok(Value::Unit),
-        // throws error if the paramter is Unit. returns original value
-        "assert" => {
-            if args.len() != 1 {
-                return Err("Arg count".to_owned());
-            }
-
-            if args[0] == Value::Unit {
-                Err("Assertion failed".to_owned())
-            } else {
-                Ok(args[0].clone())
-            }
-        },
         // anonymous function, i.e. parameter substitution
         "lambda" => {
-            let params: Result<Vec<String>, String> = args[..args.len() - 1]
+            if args.is_empty() {
+                return Err(Error::arity_min(&name, 1, 0));
+            }
+            let params: Result<Vec<String>, Error> = args[..args.len() - 1]
                 .iter()
                 .cloned()
                 .map(|a| match a {
-                    Value::Quot(box Value::Idfr(n)) => Ok(n),
-                    _ => Err("Quoted identifier required as lambda parameters".to_owned()),
+                    Value::Quot(box Value::Idfr(n), _) => Ok(n),
+                    _ => Err(Error::mismatch("Quoted identifier required as lambda parameters")),
                 })
                 .collect();
 
             let body = args[args.len() - 1].clone();
 
-            Ok(Value::Lmbd(params?, box body))
+            Ok(Value::Lmbd(params?, box body, intp.current_scope()))
+        },
+        // lambda capturing the current values of the names used in its body, so that
+        // later changes to them don't affect it: (closure 'l '(q:concat l suffix))
+        // the captured names are resolved in the scope of the closure, which code quoted in
+        // its body keeps wherever it's unquoted; builtins, symbols and unbound names aren't captured
+        "closure" => {
+            if args.is_empty() {
+                return Err(Error::arity_min(&name, 1, 0));
+            }
+            let (params, body) = match call(intp, "lambda".to_owned(), args)? {
+                Value::Lmbd(params, body, _) => (params, body),
+                _ => unreachable!("Invalid state"),
+            };
+            let mut names = Vec::new();
+            body.collect_names(&mut names);
+            let mut captured = Vec::new();
+            for n in names.into_iter().filter(|n| !params.contains(n)) {
+                match intp.resolve_here(&n) {
+                    Ok(Value::Bltn(_)) | Err(_) => {},
+                    Ok(v) => {
+                        if !module::is_symbol(&n, &v) {
+                            captured.push((n, v));
+                        }
+                    },
+                }
+            }
+            let module = intp.current_scope().and_then(|s| s.module_arc());
+            Ok(Value::Lmbd(params, body, Some(Arc::new(Scope::new(module, captured)))))
         },
         // unquote a list of statements sequentially, returning the last result
+        // the last one is unquoted by the caller, so that it's evaluated in tail position
         "block" => {
-            let mut res: Value = Value::Unit;
+            let mut args = args;
+            let last = match args.pop() {
+                Some(last) => last,
+                None => return Ok(Value::Unit),
+            };
             for a in args {
-                res = intp.execute(Value::Expr(vec![Value::Bltn("unquote".to_owned()), a.clone()]))?;
+                intp.execute(Value::Expr(vec![Value::Bltn("unquote".to_owned()), a].into()))?;
             }
-            Ok(res)
+            Ok(Value::Expr(vec![Value::Bltn("unquote".to_owned()), last].into()))
         },
-        // if-else condition select, unquotes the relevant side
+        // if-else condition select, unquotes the relevant side in tail position
         "branch" => {
             if args.len() != 3 {
-                Err("Arg count".to_owned())
+                Err(Error::arity(&name, 3, args.len()))
             } else {
                 if args[0] != Value::Unit {
                     // strict true check
-                    if args[0] != Value::Quot(box Value::Idfr("true".to_owned())) {
-                        return Err("Strict true required".to_owned());
+                    if args[0] != boolvalue!(true) {
+                        return Err(Error::mismatch("Strict true required"));
                     }
 
                     // true branch
-                    Ok(Value::Expr(vec![
-                        Value::Bltn("unquote".to_owned()),
-                        args[1].clone(),
-                    ]))
+                    Ok(Value::Expr(
+                        vec![Value::Bltn("unquote".to_owned()), args[1].clone()].into(),
+                    ))
                 } else {
                     // false branch
-                    Ok(Value::Expr(vec![
-                        Value::Bltn("unquote".to_owned()),
-                        args[2].clone(),
-                    ]))
+                    Ok(Value::Expr(
+                        vec![Value::Bltn("unquote".to_owned()), args[2].clone()].into(),
+                    ))
                 }
             }
         },
@@ -120,7 +191,7 @@ pub fn call(intp: &mut Interpreter, name: String, args: Vec<Value>) -> Result<Va
         // name resolution is done until a stop-idfr is reached
         "eq?" => {
             if args.len() != 2 {
-                Err("Arg count".to_owned())
+                Err(Error::arity(&name, 2, args.len()))
             } else {
                 if args[0] == args[1] {
                     Ok(boolvalue!(true))
@@ -143,7 +214,7 @@ pub fn call(intp: &mut Interpreter, name: String, args: Vec<Value>) -> Result<Va
         // test exact structure equality
         "eqtree?" => {
             if args.len() != 2 {
-                Err("Arg count".to_owned())
+                Err(Error::arity(&name, 2, args.len()))
             } else {
                 Ok(boolvalue!(args[0] == args[1]))
             }
@@ -151,137 +222,350 @@ pub fn call(intp: &mut Interpreter, name: String, args: Vec<Value>) -> Result<Va
         // reverse quoted expression
         "q:reverse" => {
             if args.len() != 1 {
-                Err("Arg count".to_owned())
+                Err(Error::arity(&name, 1, args.len()))
             } else {
-                if let Value::Quot(box Value::Expr(e)) = args[0].clone() {
-                    Ok(Value::Quot(box Value::Expr(e.iter().rev().cloned().collect())))
-                } else if let Value::Quot(box Value::Unit) = args[0] {
-                    Ok(Value::Quot(box Value::Unit))
+                if let Value::Quot(box Value::Expr(e), m) = args[0].clone() {
+                    Ok(Value::Quot(box Value::Expr(e.iter().rev().cloned().collect()), m))
+                } else if let Value::Quot(box Value::Unit, _) = args[0] {
+                    Ok(args[0].clone())
                 } else {
-                    Err("Quoted expression required".to_owned())
+                    Err(Error::mismatch("Quoted expression required"))
                 }
             }
         },
         // concatenate two quoted expressions
         "q:concat" => {
             if args.len() != 2 {
-                Err("Arg count".to_owned())
+                Err(Error::arity(&name, 2, args.len()))
             } else {
-                if let Value::Quot(box Value::Expr(e0)) = args[0].clone() {
-                    if let Value::Quot(box Value::Expr(e1)) = args[1].clone() {
-                        Ok(Value::Quot(box Value::Expr(
-                            e0.iter().chain(e1.iter()).cloned().collect(),
-                        )))
-                    } else if let Value::Quot(box Value::Unit) = args[1] {
+                if let Value::Quot(box Value::Expr(e0), m) = args[0].clone() {
+                    if let Value::Quot(box Value::Expr(e1), _) = args[1].clone() {
+                        Ok(Value::Quot(
+                            box Value::Expr(e0.iter().chain(e1.iter()).cloned().collect()),
+                            m,
+                        ))
+                    } else if let Value::Quot(box Value::Unit, _) = args[1] {
                         Ok(args[0].clone())
                     } else {
-                        Err("Quoted expression required".to_owned())
+                        Err(Error::mismatch("Quoted expression required"))
                     }
-                } else if let Value::Quot(box Value::Unit) = args[0] {
+                } else if let Value::Quot(box Value::Unit, _) = args[0] {
                     Ok(args[1].clone())
                 } else {
-                    Err("Quoted expression required".to_owned())
+                    Err(Error::mismatch("Quoted expression required"))
                 }
             }
         },
         // get first item from a quoted expression
         "q:head" => {
             if args.len() != 1 {
-                Err("Arg count".to_owned())
+                Err(Error::arity(&name, 1, args.len()))
             } else {
-                if let Value::Quot(box Value::Expr(e0)) = args[0].clone() {
-                    Ok(e0[0].clone())
-                } else if let Value::Quot(box Value::Unit) = args[0] {
-                    Err("Cannot get first element of an empty list".to_owned())
-                } else {
-                    Err("Quoted expression required".to_owned())
+                match &args[0] {
+                    Value::Quot(box Value::Expr(e0), _) if !e0.is_empty() => Ok(e0[0].clone()),
+                    Value::Quot(box Value::Expr(_), _) | Value::Quot(box Value::Unit, _) => {
+                        Err(Error::OutOfRange("Cannot get first element of an empty list".to_owned()))
+                    },
+                    _ => Err(Error::mismatch("Quoted expression required")),
                 }
             }
         },
         // remove first item from a quoted expression
         "q:tail" => {
             if args.len() != 1 {
-                Err("Arg count".to_owned())
+                Err(Error::arity(&name, 1, args.len()))
+            } else {
+                if let Value::Quot(box Value::Expr(e), m) = args[0].clone() {
+                    if e.len() <= 1 {
+                        Ok(Value::Quot(box Value::Unit, m))
+                    } else {
+                        Ok(Value::Quot(box Value::Expr(e.tail()), m))
+                    }
+                } else if let Value::Quot(box Value::Unit, _) = args[0] {
+                    Ok(args[0].clone())
+                } else {
+                    Err(Error::mismatch("Quoted expression required"))
+                }
+            }
+        },
+        // integer arithmetic, failing on overflow: (i:add 1 2)
+        "i:add" => {
+            let (a, b) = int_args(&name, &args)?;
+            a.checked_add(b)
+                .map(Value::Int)
+                .ok_or(Error::IntegerOverflow(name))
+        },
+        "i:sub" => {
+            let (a, b) = int_args(&name, &args)?;
+            a.checked_sub(b)
+                .map(Value::Int)
+                .ok_or(Error::IntegerOverflow(name))
+        },
+        "i:mul" => {
+            let (a, b) = int_args(&name, &args)?;
+            a.checked_mul(b)
+                .map(Value::Int)
+                .ok_or(Error::IntegerOverflow(name))
+        },
+        // integer division rounding towards zero, and its remainder
+        "i:div" => {
+            let (a, b) = int_args(&name, &args)?;
+            if b == 0 {
+                return Err(Error::DivisionByZero);
+            }
+            a.checked_div(b)
+                .map(Value::Int)
+                .ok_or(Error::IntegerOverflow(name))
+        },
+        "i:rem" => {
+            let (a, b) = int_args(&name, &args)?;
+            if b == 0 {
+                return Err(Error::DivisionByZero);
+            }
+            a.checked_rem(b)
+                .map(Value::Int)
+                .ok_or(Error::IntegerOverflow(name))
+        },
+        // integer comparison
+        "i:lt?" => {
+            let (a, b) = int_args(&name, &args)?;
+            Ok(boolvalue!(a < b))
+        },
+        "i:gt?" => {
+            let (a, b) = int_args(&name, &args)?;
+            Ok(boolvalue!(a > b))
+        },
+        // test if the value is an integer
+        "i:int?" => {
+            if args.len() != 1 {
+                Err(Error::arity(&name, 1, args.len()))
+            } else {
+                Ok(match args[0] {
+                    Value::Int(_) => boolvalue!(true),
+                    _ => boolvalue!(false),
+                })
+            }
+        },
+        // length of a string in characters
+        "s:length" => {
+            if args.len() != 1 {
+                Err(Error::arity(&name, 1, args.len()))
             } else {
-                if let Value::Quot(box Value::Expr(e)) = args[0].clone() {
-                    if e.len() == 1 {
-                        Ok(Value::Quot(box Value::Unit))
+                Ok(Value::Int(str_arg(&args[0])?.chars().count() as i64))
+            }
+        },
+        // concatenate any number of strings
+        "s:concat" => {
+            let mut result = String::new();
+            for a in &args {
+                result.push_str(str_arg(a)?);
+            }
+            Ok(Value::Str(result))
+        },
+        // characters from start index up to, but not including, end index: (s:substring "abc" 1 2)
+        "s:substring" => {
+            if args.len() != 3 {
+                return Err(Error::arity(&name, 3, args.len()));
+            }
+            let chars: Vec<char> = str_arg(&args[0])?.chars().collect();
+            match (&args[1], &args[2]) {
+                (&Value::Int(start), &Value::Int(end)) => {
+                    if start < 0 || end < start || end as usize > chars.len() {
+                        Err(Error::OutOfRange("Substring range out of bounds".to_owned()))
                     } else {
-                        Ok(Value::Quot(box Value::Expr(e[1..].to_vec())))
+                        Ok(Value::Str(chars[start as usize..end as usize].iter().collect()))
                     }
-                } else if let Value::Quot(box Value::Unit) = args[0] {
-                    Ok(Value::Quot(box Value::Unit))
+                },
+                _ => Err(Error::mismatch("Integer indices required")),
+            }
+        },
+        // split a string by a separator to a quoted list of strings: (s:split "a,b" ",")
+        "s:split" => {
+            if args.len() != 2 {
+                return Err(Error::arity(&name, 2, args.len()));
+            }
+            let separator = str_arg(&args[1])?;
+            if separator.is_empty() {
+                return Err(Error::InvalidArgument("Separator must not be empty".to_owned()));
+            }
+            let parts = str_arg(&args[0])?
+                .split(separator)
+                .map(|p| Value::Str(p.to_owned()))
+                .collect();
+            Ok(Value::Quot(box Value::Expr(parts), None))
+        },
+        // join a quoted list of strings with a separator: (s:join (quote ("a" "b")) ",")
+        "s:join" => {
+            if args.len() != 2 {
+                return Err(Error::arity(&name, 2, args.len()));
+            }
+            let separator = str_arg(&args[1])?;
+            let parts = match &args[0] {
+                Value::Quot(box Value::Unit, _) => Vec::new(),
+                Value::Quot(box Value::Expr(e), _) => e.iter().map(str_arg).collect::<Result<Vec<_>, _>>()?,
+                _ => return Err(Error::mismatch("Quoted list of strings required")),
+            };
+            Ok(Value::Str(parts.join(separator)))
+        },
+        // quoted identifier with the string as its name
+        "s:to_symbol" => {
+            if args.len() != 1 {
+                Err(Error::arity(&name, 1, args.len()))
+            } else {
+                let symbol = str_arg(&args[0])?;
+                // Hidden names of module bindings can't be written in source, and must not be made here
+                if module::is_hidden(symbol) {
+                    return Err(Error::InvalidArgument(
+                        "Symbol must not name a hidden module binding".to_owned(),
+                    ));
+                }
+                Ok(Value::Quot(box Value::Idfr(symbol.to_owned()), intp.current_scope()))
+            }
+        },
+        // name of a quoted identifier as a string
+        "s:from_symbol" => {
+            if args.len() != 1 {
+                Err(Error::arity(&name, 1, args.len()))
+            } else {
+                if let Value::Quot(box Value::Idfr(n), _) = &args[0] {
+                    Ok(Value::Str(n.clone()))
                 } else {
-                    Err("Quoted expression required".to_owned())
+                    Err(Error::mismatch("Quoted identifier required"))
                 }
             }
         },
+        // test if the value is a string
+        "s:str?" => {
+            if args.len() != 1 {
+                Err(Error::arity(&name, 1, args.len()))
+            } else {
+                Ok(match args[0] {
+                    Value::Str(_) => boolvalue!(true),
+                    _ => boolvalue!(false),
+                })
+            }
+        },
         // test if the top-level item in quotes is empty, i.e Unit
         "q:empty?" => {
             if args.len() != 1 {
-                Err("Arg count".to_owned())
+                Err(Error::arity(&name, 1, args.len()))
             } else {
-                if let Value::Quot(box arg) = &args[0] {
-                    Ok(boolvalue!(*arg == Value::Unit))
+                if let Value::Quot(box arg, _) = &args[0] {
+                    Ok(boolvalue!(match arg {
+                        Value::Unit => true,
+                        Value::Expr(e) => e.is_empty(),
+                        _ => false,
+                    }))
                 } else {
-                    Err("Quoted value required as argument".to_owned())
+                    Err(Error::mismatch("Quoted value required as argument"))
                 }
             }
         },
         // test if the top-level item in quotes is an expression
         "q:expr?" => {
             if args.len() != 1 {
-                Err("Arg count".to_owned())
+                Err(Error::arity(&name, 1, args.len()))
             } else {
-                if let Value::Quot(box arg) = &args[0] {
+                if let Value::Quot(box arg, _) = &args[0] {
                     Ok(match arg {
                         Value::Unit | Value::Expr(_) => boolvalue!(true),
                         _ => boolvalue!(false),
                     })
                 } else {
-                    Err("Quoted value required as argument".to_owned())
+                    Err(Error::mismatch("Quoted value required as argument"))
                 }
             }
         },
         // bind a value to a name, and return the value: (bind (quote another_true) true)
+        // the name is bound in the module of the scope it was quoted in
         "set" => {
             if args.len() != 2 {
-                Err("Arg count".to_owned())
+                Err(Error::arity(&name, 2, args.len()))
             } else {
-                if let Value::Quot(q) = args[0].clone() {
+                if let Value::Quot(q, scope) = args[0].clone() {
                     if let Value::Idfr(n) = (*q).clone() {
+                        let n = intp.name_in(scope.as_deref(), &n).to_owned();
                         intp.bind(n, args[1].clone());
                         Ok(args[1].clone())
                     } else {
-                        Err("Must bind to a quoted identifier".to_owned())
+                        Err(Error::mismatch("Must bind to a quoted identifier"))
                     }
                 } else {
-                    Err("Must bind to a quoted identifier".to_owned())
+                    Err(Error::mismatch("Must bind to a quoted identifier"))
                 }
             }
         },
         // delete a symbol from namespace: (del (quote value_name))
         "del" => {
             if args.len() != 1 {
-                Err("Arg count".to_owned())
+                Err(Error::arity(&name, 1, args.len()))
             } else {
-                if let Value::Quot(q) = args[0].clone() {
+                if let Value::Quot(q, scope) = args[0].clone() {
                     if let Value::Idfr(n) = (*q).clone() {
+                        let n = intp.name_in(scope.as_deref(), &n).to_owned();
                         intp.delete(&n);
                         Ok(Value::Unit)
                     } else {
-                        Err("Can only delete a quoted identifier".to_owned())
+                        Err(Error::mismatch("Can only delete a quoted identifier"))
                     }
                 } else {
-                    Err("Can only delete a quoted identifier".to_owned())
+                    Err(Error::mismatch("Can only delete a quoted identifier"))
                 }
             }
         },
+        // import a module, optionally binding its names with a prefix: (import (quote peano) (quote n:))
+        "import" => {
+            if args.is_empty() || args.len() > 2 {
+                Err(Error::arity_range(&name, 1..=2, args.len()))
+            } else {
+                let prefix = match args.get(1) {
+                    Some(Value::Quot(box Value::Idfr(p), _)) => p.as_str(),
+                    Some(_) => return Err(Error::mismatch("Import prefix must be a quoted identifier")),
+                    None => "",
+                };
+                if let Value::Quot(box Value::Idfr(n), _) = &args[0] {
+                    intp.import_prefixed(n, prefix)?;
+                    Ok(Value::Unit)
+                } else {
+                    Err(Error::mismatch("Can only import a quoted identifier"))
+                }
+            }
+        },
+        // declare names public in the current module: (export (quote (p:add p:sub)))
+        "export" => {
+            if args.len() != 1 {
+                Err(Error::arity(&name, 1, args.len()))
+            } else {
+                match &args[0] {
+                    Value::Quot(box Value::Idfr(n), _) => intp.export(std::slice::from_ref(n))?,
+                    Value::Quot(box Value::Expr(e), _) => {
+                        let mut names = Vec::new();
+                        for v in e {
+                            match v {
+                                Value::Idfr(n) => names.push(n.clone()),
+                                _ => return Err(Error::mismatch("Can only export identifiers")),
+                            }
+                        }
+                        intp.export(&names)?
+                    },
+                    _ => return Err(Error::mismatch("Can only export quoted identifiers")),
+                }
+                Ok(Value::Unit)
+            }
+        },
         // print the arguments and a line break, returning Unit
+        // strings are printed as-is, without quotes or escapes
         "println" => {
-            println!("{}", args.iter().map(Value::format).collect::<Vec<_>>().join(" "));
+            let parts: Vec<String> = args
+                .iter()
+                .map(|a| match a {
+                    Value::Str(s) => s.clone(),
+                    other => other.format(),
+                })
+                .collect();
+            writeln!(intp.output(), "{}", parts.join(" "))?;
             Ok(Value::Unit)
         },
-        n => Err(format!("Function {} is not yet defined", n)),
+        n => Err(Error::UnboundIdentifier(n.to_owned())),
     }
 }
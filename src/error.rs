@@ -0,0 +1,341 @@
+//! Errors
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::ops::RangeInclusive;
+
+use super::parser::Span;
+use super::value::Value;
+
+/// Any error produced while parsing or executing code
+#[derive(Debug)]
+pub enum Error {
+    /// A builtin was called with a wrong number of arguments
+    ArityMismatch {
+        /// Name of the builtin
+        name: String,
+        /// Numbers of arguments accepted
+        expected: RangeInclusive<usize>,
+        /// Number of arguments given
+        got: usize,
+    },
+    /// Name resolution failed
+    UnboundIdentifier(String),
+    /// A value of the wrong kind was given, with a description of what was required
+    TypeMismatch(String),
+    /// An index or a range is outside of the value it's used on, with a description
+    OutOfRange(String),
+    /// A value of the right kind was given, but it isn't accepted, with a description of why
+    InvalidArgument(String),
+    /// `export` was used outside of a module being loaded
+    ExportOutsideModule,
+    /// Raised by the `error` builtin, containing its arguments
+    UserError(Value),
+    /// Raised by the `assert` builtin, with the asserted expression
+    AssertionFailed(Value),
+    /// Result of an integer operation doesn't fit, with the name of the operation
+    IntegerOverflow(String),
+    /// Integer division or remainder with a zero divisor
+    DivisionByZero,
+    /// The evaluation step limit set with `Interpreter::set_fuel` was reached
+    OutOfFuel {
+        /// Steps taken in total
+        steps: u64,
+    },
+    /// Evaluation was stopped through a `CancelToken`
+    Cancelled,
+    /// The deadline set with `Interpreter::set_deadline` passed during evaluation
+    DeadlineExceeded,
+    /// Evaluation nested deeper than the interpreter allows
+    RecursionLimit {
+        /// Nesting depth reached
+        depth: usize,
+        /// The value that would have been evaluated next
+        value: Value,
+    },
+    /// Evaluation made a value nested deeper than the interpreter allows
+    NestingLimit {
+        /// Nesting depth reached
+        depth: usize,
+    },
+    /// Source code is not syntactically valid
+    ParseError(String),
+    /// No module with the given name exists
+    UnknownModule(String),
+    /// Modules import each other, listed starting and ending with the same module
+    ImportCycle(Vec<String>),
+    /// Source file could not be read
+    IoError(io::Error),
+    /// Another error raised while loading an imported module
+    Importing {
+        /// Name of the module
+        module: String,
+        /// Location of the import, if it's known
+        site: Option<String>,
+        /// The actual error
+        error: Box<Error>,
+    },
+    /// Another error and the evaluation call stack when it was raised
+    Traced {
+        /// Functions being applied, outermost first
+        trace: Vec<TraceFrame>,
+        /// The actual error
+        error: Box<Error>,
+    },
+    /// Another error and the source location it was caused by
+    Located {
+        /// File name, if the source came from a file
+        origin: Option<String>,
+        /// Location in the source
+        span: Span,
+        /// The actual error
+        error: Box<Error>,
+    },
+}
+impl Error {
+    /// Shorthand constructor for `ArityMismatch`
+    pub(crate) fn arity(name: &str, expected: usize, got: usize) -> Self {
+        Self::arity_range(name, expected..=expected, got)
+    }
+
+    /// Shorthand constructor for `ArityMismatch` of a builtin taking a varying number of arguments
+    pub(crate) fn arity_range(name: &str, expected: RangeInclusive<usize>, got: usize) -> Self {
+        Error::ArityMismatch {
+            name: name.to_owned(),
+            expected,
+            got,
+        }
+    }
+
+    /// Shorthand constructor for `ArityMismatch` of a builtin taking any number of arguments
+    /// from a minimum up. The accepted range ends at `usize::MAX`.
+    pub(crate) fn arity_min(name: &str, min: usize, got: usize) -> Self {
+        Self::arity_range(name, min..=usize::MAX, got)
+    }
+
+    /// Shorthand constructor for `TypeMismatch`
+    pub(crate) fn mismatch(description: &str) -> Self {
+        Error::TypeMismatch(description.to_owned())
+    }
+
+    /// Attach a source location to the error
+    pub(crate) fn at(self, origin: Option<&str>, span: Span) -> Self {
+        Error::Located {
+            origin: origin.map(str::to_owned),
+            span,
+            error: Box::new(self),
+        }
+    }
+
+    /// Record where the module whose loading failed was imported, unless that's known already
+    pub(crate) fn imported_at(self, origin: Option<&str>, span: Span) -> Self {
+        match self {
+            Error::Importing {
+                module,
+                site: None,
+                error,
+            } => Error::Importing {
+                module,
+                site: Some(site(origin, span)),
+                error,
+            },
+            Error::Traced { trace, error } => Error::Traced {
+                trace,
+                error: Box::new(error.imported_at(origin, span)),
+            },
+            other => other,
+        }
+    }
+
+    /// Whether a source location is attached to the error
+    pub(crate) fn is_located(&self) -> bool {
+        match self {
+            Error::Located { .. } | Error::Importing { .. } => true,
+            Error::Traced { error, .. } => error.is_located(),
+            _ => false,
+        }
+    }
+
+    /// Fill in the file name of a location that is missing one
+    pub(crate) fn in_file(self, name: &str) -> Self {
+        match self {
+            Error::Located {
+                origin: None,
+                span,
+                error,
+            } => Error::Located {
+                origin: Some(name.to_owned()),
+                span,
+                error,
+            },
+            other => other,
+        }
+    }
+
+    /// The underlying error, without location or trace information
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Located { error, .. } => error.root(),
+            Error::Traced { error, .. } => error.root(),
+            Error::Importing { error, .. } => error.root(),
+            other => other,
+        }
+    }
+
+    /// Evaluation call stack at the point the error was raised, outermost first
+    pub fn trace(&self) -> Option<&[TraceFrame]> {
+        match self {
+            Error::Located { error, .. } => error.trace(),
+            Error::Importing { error, .. } => error.trace(),
+            Error::Traced { trace, .. } => Some(trace),
+            _ => None,
+        }
+    }
+
+    /// Call stack as lines like `in f at file.mls:3:5`, outermost first. Repeats of the same
+    /// frame, as in recursion, are shown once with their count, like `in f at 3:5 (×200)`.
+    pub fn trace_lines(&self) -> Vec<String> {
+        let mut frames: Vec<(&TraceFrame, usize)> = Vec::new();
+        for frame in self.trace().unwrap_or_default() {
+            match frames.last_mut() {
+                Some((last, count)) if *last == frame => *count += 1,
+                _ => frames.push((frame, 1)),
+            }
+        }
+        frames
+            .into_iter()
+            .map(|(frame, count)| match count {
+                1 => format!("in {}", frame),
+                _ => format!("in {} (×{})", frame, count),
+            })
+            .collect()
+    }
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ArityMismatch { name, expected, got } if expected.start() == expected.end() => {
+                write!(
+                    f,
+                    "Arg count: {} takes {} {}, got {}",
+                    name,
+                    expected.start(),
+                    plural(*expected.start(), "argument"),
+                    got
+                )
+            },
+            Error::ArityMismatch { name, expected, got } if *expected.end() == usize::MAX => write!(
+                f,
+                "Arg count: {} takes at least {} {}, got {}",
+                name,
+                expected.start(),
+                plural(*expected.start(), "argument"),
+                got
+            ),
+            Error::ArityMismatch { name, expected, got } => write!(
+                f,
+                "Arg count: {} takes {} to {} arguments, got {}",
+                name,
+                expected.start(),
+                expected.end(),
+                got
+            ),
+            Error::UnboundIdentifier(name) => write!(f, "Resolution failed '{}'", name),
+            Error::TypeMismatch(description) => write!(f, "{}", description),
+            Error::OutOfRange(description) => write!(f, "{}", description),
+            Error::InvalidArgument(description) => write!(f, "{}", description),
+            Error::ExportOutsideModule => write!(f, "Can only export from a module"),
+            Error::UserError(value) => write!(f, "Runtime Error: {}", value),
+            Error::AssertionFailed(expr) => write!(f, "Assertion failed: {}", expr.format()),
+            Error::IntegerOverflow(name) => write!(f, "Integer overflow in {}", name),
+            Error::DivisionByZero => write!(f, "Division by zero"),
+            Error::OutOfFuel { steps } => write!(f, "Out of fuel after {} steps", steps),
+            Error::Cancelled => write!(f, "Evaluation cancelled"),
+            Error::DeadlineExceeded => write!(f, "Evaluation deadline exceeded"),
+            Error::RecursionLimit { depth, value } => {
+                let mut code = value.format();
+                if code.chars().count() > 60 {
+                    code = code.chars().take(57).collect::<String>() + "...";
+                }
+                write!(f, "Recursion limit exceeded at depth {}: {}", depth, code)
+            },
+            Error::NestingLimit { depth } => write!(f, "Nesting limit exceeded at depth {}", depth),
+            Error::ParseError(description) => write!(f, "Parse error: {}", description),
+            Error::UnknownModule(name) => write!(f, "Unknown module '{}'", name),
+            Error::ImportCycle(modules) => write!(f, "Import cycle: {}", modules.join(" -> ")),
+            Error::IoError(e) => write!(f, "IO error: {}", e),
+            Error::Traced { error, .. } => write!(f, "{}", error),
+            Error::Importing {
+                module,
+                site: Some(site),
+                error,
+            } => write!(f, "{}, while importing {} at {}", error, module, site),
+            Error::Importing {
+                module,
+                site: None,
+                error,
+            } => write!(f, "{}, while importing {}", error, module),
+            Error::Located {
+                origin: Some(name),
+                span,
+                error,
+            } => write!(f, "{}:{}: {}", name, span, error),
+            Error::Located {
+                origin: None,
+                span,
+                error,
+            } => write!(f, "{}: {}", span, error),
+        }
+    }
+}
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::IoError(e) => Some(e),
+            Error::Importing { error, .. } => Some(error.as_ref()),
+            Error::Traced { error, .. } => Some(error.as_ref()),
+            Error::Located { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::IoError(e)
+    }
+}
+
+/// Application of a function in an evaluation call stack
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    /// Name the function was applied by, or `<lambda>` for an anonymous one
+    pub name: String,
+    /// Where it was applied, like `file.mls:3:5`, if the application was parsed from source
+    pub site: Option<String>,
+}
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.site {
+            Some(site) => write!(f, "{} at {}", self.name, site),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// Source location as text, with the file name if any
+pub(crate) fn site(origin: Option<&str>, span: Span) -> String {
+    match origin {
+        Some(name) => format!("{}:{}", name, span),
+        None => span.to_string(),
+    }
+}
+
+/// Noun in the form matching the count
+fn plural(count: usize, noun: &str) -> String {
+    if count == 1 {
+        noun.to_owned()
+    } else {
+        format!("{}s", noun)
+    }
+}
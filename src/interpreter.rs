@@ -1,31 +1,219 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::fs;
-use std::path::Path;
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, MutexGuard};
+use std::time::Instant;
 
 use super::corelib;
-use super::parser;
-use super::value::Value;
+use super::error::{self, Error, TraceFrame};
+use super::module::{self, ModuleScope};
+use super::output::Output;
+use super::parser::{self, Location};
+use super::stdlib;
+use super::value::{Scope, Value};
+
+/// Default for `Interpreter::max_depth`. Fits in 2 MiB, the default stack size of spawned threads,
+/// even in debug builds, which take about 8 KiB of stack per level.
+const DEFAULT_MAX_DEPTH: usize = 200;
+
+/// Cancellation and the deadline are checked once per this many evaluation steps, a power of two
+const INTERRUPT_CHECK_INTERVAL: u64 = 256;
+
+/// Handle for stopping a running evaluation from another thread
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<CancelState>);
+
+/// Runs are numbered, and a cancellation request is for the run that was in progress when it was made
+#[derive(Debug, Default)]
+struct CancelState {
+    /// Counts starts and ends of runs, so it's odd while one is in progress, numbering it
+    run: AtomicU64,
+    /// Number of the run cancellation was requested for, zero if none
+    cancelled: AtomicU64,
+}
+
+impl CancelToken {
+    /// Request the running evaluation to stop. A run is a call to any of the public methods of
+    /// `Interpreter` that evaluate code, and the request is consumed by the run it stops.
+    /// Nothing is stopped if no run is in progress, and a request for a run that finishes
+    /// before noticing it is dropped, so a late request never stops a later run.
+    pub fn cancel(&self) {
+        let run = self.0.run.load(Ordering::SeqCst);
+        if run % 2 == 1 {
+            self.0.cancelled.store(run, Ordering::SeqCst);
+        }
+    }
+
+    /// Whether cancellation has been requested, and not yet consumed
+    pub fn is_cancelled(&self) -> bool {
+        let run = self.0.run.load(Ordering::SeqCst);
+        run % 2 == 1 && self.0.cancelled.load(Ordering::SeqCst) == run
+    }
+
+    /// Withdraw a pending cancellation request
+    pub fn reset(&self) {
+        self.0.cancelled.store(0, Ordering::SeqCst);
+    }
+
+    /// Consume a pending cancellation request
+    fn take(&self) -> bool {
+        let run = self.0.run.load(Ordering::SeqCst);
+        self.0
+            .cancelled
+            .compare_exchange(run, 0, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    /// Number a run that is starting
+    fn start_run(&self) {
+        self.0.run.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// End the run in progress, dropping requests for it
+    fn finish_run(&self) {
+        self.0.run.fetch_add(1, Ordering::SeqCst);
+    }
+}
 
 /// Language interpreter
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Interpreter {
     namespace: HashMap<String, Value>,
+    call_stack: Vec<(String, Option<Arc<Location>>)>,
     exec_depth: usize,
+    max_depth: usize,
+    max_nesting: usize,
+    fuel: Option<u64>,
+    steps: u64,
+    cancel: CancelToken,
+    running: bool,
+    deadline: Option<Instant>,
+    output: Output,
     debug_print: bool,
+    stdlib_dir: Option<PathBuf>,
+    prelude: Vec<String>,
+    module_dirs: Vec<PathBuf>,
+    modules: HashMap<String, Vec<String>>,
+    module_envs: HashMap<String, HashMap<String, String>>,
+    module_scopes: Vec<ModuleScope>,
+    scope: Option<Arc<Scope>>,
+}
+impl Clone for Interpreter {
+    /// Copy of the interpreter state, with a cancellation token of its own, so that cancelling
+    /// one copy doesn't stop the others
+    fn clone(&self) -> Self {
+        Self {
+            namespace: self.namespace.clone(),
+            call_stack: self.call_stack.clone(),
+            exec_depth: self.exec_depth,
+            max_depth: self.max_depth,
+            max_nesting: self.max_nesting,
+            fuel: self.fuel,
+            steps: self.steps,
+            cancel: CancelToken::default(),
+            running: self.running,
+            deadline: self.deadline,
+            output: self.output.clone(),
+            debug_print: self.debug_print,
+            stdlib_dir: self.stdlib_dir.clone(),
+            prelude: self.prelude.clone(),
+            module_dirs: self.module_dirs.clone(),
+            modules: self.modules.clone(),
+            module_envs: self.module_envs.clone(),
+            module_scopes: self.module_scopes.clone(),
+            scope: self.scope.clone(),
+        }
+    }
 }
 impl Interpreter {
-    /// Create new, empty interpreter
+    /// Create new, empty interpreter.
+    ///
+    /// By default evaluation can nest 200 levels deep, see `max_depth`. That fits in the 2 MiB
+    /// stack of a spawned thread, but means that recursion not in tail position fails with
+    /// `RecursionLimit` after about 200 calls, e.g. over a list of a few hundred items.
+    /// Deeper recursion needs a higher limit and a thread with a bigger stack, like the
+    /// command line interpreter has. Values can nest `parser::MAX_NESTING` levels deep,
+    /// see `max_nesting`.
     pub fn new() -> Self {
         Self {
             namespace: HashMap::new(),
+            call_stack: Vec::new(),
             exec_depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_nesting: parser::MAX_NESTING,
+            fuel: None,
+            steps: 0,
+            cancel: CancelToken::default(),
+            running: false,
+            deadline: None,
+            output: Output::default(),
             debug_print: false,
+            stdlib_dir: None,
+            prelude: stdlib::MODULES
+                .iter()
+                .filter(|m| m.prelude)
+                .map(|m| m.name.to_owned())
+                .collect(),
+            module_dirs: Vec::new(),
+            modules: HashMap::new(),
+            module_envs: HashMap::new(),
+            module_scopes: Vec::new(),
+            scope: None,
         }
     }
 
+    /// Limit how deeply evaluation can nest before failing with `RecursionLimit`.
+    /// Each level takes some native stack, so a high limit needs a thread with a big stack.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Limit how deeply expressions, quotes and lambdas can nest in values made by evaluation
+    /// before failing with `NestingLimit`, and in parsed source before failing with `ParseError`.
+    /// By default values can nest `parser::MAX_NESTING` levels deep.
+    /// Values are cloned, compared, printed and dropped recursively, so a high limit needs
+    /// a thread with a big stack too.
+    pub fn max_nesting(mut self, depth: usize) -> Self {
+        self.max_nesting = depth;
+        self
+    }
+
+    /// Add a directory to search for imported modules, after the stdlib
+    pub fn module_dir<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.module_dirs.push(path.as_ref().to_owned());
+        self
+    }
+
+    /// Choose the stdlib modules loaded by `init`, by default all except `testing`.
+    /// Modules they import are loaded as well.
+    pub fn prelude(mut self, modules: &[&str]) -> Self {
+        self.prelude = modules.iter().map(|&m| m.to_owned()).collect();
+        self
+    }
+
+    /// Don't load any stdlib modules in `init`, only bind corelib builtins
+    pub fn no_prelude(self) -> Self {
+        self.prelude(&[])
+    }
+
+    /// Load stdlib modules from source files in a directory instead of the embedded copies
+    pub fn stdlib_dir<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.stdlib_dir = Some(path.as_ref().to_owned());
+        self
+    }
+
     /// Bind corelib and stdlib functions
-    pub fn init(mut self) -> Self {
+    pub fn init(self) -> Self {
+        self.try_init().expect("STDLIB ERROR")
+    }
+
+    /// Bind corelib and stdlib functions, failing if stdlib cannot be loaded
+    pub fn try_init(mut self) -> Result<Self, Error> {
         // Corelib builtins
         for &name in corelib::BUILTINS.iter() {
             let n: String = name.into();
@@ -33,12 +221,188 @@ impl Interpreter {
         }
 
         // Stdlib / prelude imports
-        // self.debug_print = true;
-        self.execute_file("src/stdlib/logic.mls").expect("STDLIB ERROR");
-        self.execute_file("src/stdlib/peano.mls").expect("STDLIB ERROR");
-        self.execute_file("src/stdlib/qexpr.mls").expect("STDLIB ERROR");
+        self.run(|intp| {
+            for name in intp.prelude.clone() {
+                intp.import(&name)?;
+            }
+            Ok(())
+        })?;
 
-        self
+        Ok(self)
+    }
+
+    /// Import the public names of a module, loading it first unless it's already loaded.
+    /// Stdlib modules are searched first, then the module directories in order.
+    pub fn import(&mut self, name: &str) -> Result<(), Error> {
+        self.import_prefixed(name, "")
+    }
+
+    /// Import the public names of a module, binding them with a prefix
+    pub fn import_prefixed(&mut self, name: &str, prefix: &str) -> Result<(), Error> {
+        self.run(|intp| intp.import_module(name, prefix))
+    }
+
+    /// Load a module unless it's already loaded, and bind its public names with a prefix
+    fn import_module(&mut self, name: &str, prefix: &str) -> Result<(), Error> {
+        if !self.modules.contains_key(name) {
+            if let Some(index) = self.module_scopes.iter().position(|m| m.name == name) {
+                let mut cycle: Vec<String> = self.module_scopes[index..]
+                    .iter()
+                    .map(|m| m.name.clone())
+                    .collect();
+                cycle.push(name.to_owned());
+                return Err(Error::ImportCycle(cycle));
+            }
+
+            // Start the module with only builtins, symbols and hidden names visible
+            let base: HashMap<String, Value> = self
+                .namespace
+                .iter()
+                .filter(|(n, v)| module::is_shared(n, v))
+                .map(|(n, v)| (n.clone(), v.clone()))
+                .collect();
+            let outer = mem::replace(&mut self.namespace, base.clone());
+            self.module_scopes.push(ModuleScope {
+                name: name.to_owned(),
+                exports: None,
+                aliases: HashMap::new(),
+                inherited: HashSet::new(),
+                base,
+                outer,
+            });
+
+            let module_scope = Scope::new(Some(Arc::from(name)), Vec::new());
+            let outer_scope = self.scope.replace(Arc::new(module_scope));
+            let result = self.load_module(name);
+            self.scope = outer_scope;
+            let mut scope = self.module_scopes.pop().expect("Module scope missing");
+            let outer = mem::take(&mut scope.outer);
+            let module_ns = mem::replace(&mut self.namespace, outer);
+            result
+                .and_then(|()| self.finish_module(scope, module_ns))
+                .map_err(|e| match e {
+                    Error::UnknownModule(ref m) if m == name => e,
+                    e => Error::Importing {
+                        module: name.to_owned(),
+                        site: None,
+                        error: Box::new(e),
+                    },
+                })?;
+        }
+
+        for export in self.modules[name].clone() {
+            let hidden = module::hidden_name(name, &export);
+            let value = self.resolve(&hidden)?;
+            let alias = format!("{}{}", prefix, export);
+            if let Some(scope) = self.module_scopes.last_mut() {
+                scope.aliases.insert(alias.clone(), hidden);
+            }
+            self.bind(alias, value);
+        }
+        Ok(())
+    }
+
+    /// Store the bindings a loaded module made under hidden names, and record its exports and
+    /// the hidden names its code resolves names to
+    fn finish_module(&mut self, scope: ModuleScope, module_ns: HashMap<String, Value>) -> Result<(), Error> {
+        let mut renames = HashMap::new();
+        let mut defined = Vec::new();
+        for (n, v) in &module_ns {
+            if scope.base.get(n) == Some(v) {
+                continue;
+            }
+            if module::is_hidden(n) || scope.inherited.contains(n) {
+                // Made by a module imported here, pass it on
+                self.bind_inherited(n.clone(), v.clone());
+                continue;
+            }
+            match scope.aliases.get(n) {
+                Some(hidden) if module_ns.get(hidden) == Some(v) => {
+                    renames.insert(n.clone(), hidden.clone());
+                },
+                _ => {
+                    if !module::is_symbol(n, v) {
+                        renames.insert(n.clone(), module::hidden_name(&scope.name, n));
+                    }
+                    defined.push(n.clone());
+                },
+            }
+        }
+
+        for n in &defined {
+            let value = module_ns[n].clone();
+            if module::is_symbol(n, &value) {
+                self.bind_inherited(n.clone(), value.clone());
+            }
+            self.bind(module::hidden_name(&scope.name, n), value);
+        }
+        self.module_envs.insert(scope.name.clone(), renames);
+
+        let exports = match scope.exports {
+            Some(exports) => {
+                if let Some(missing) = exports.iter().find(|e| !defined.contains(e)) {
+                    return Err(Error::UnboundIdentifier(missing.clone()));
+                }
+                exports
+            },
+            None => {
+                defined.sort();
+                defined
+            },
+        };
+        self.modules.insert(scope.name, exports);
+        Ok(())
+    }
+
+    /// Bind a name made by loading a module, so that it's passed on to the importing module too
+    fn bind_inherited(&mut self, name: String, value: Value) {
+        if let Some(scope) = self.module_scopes.last_mut() {
+            scope.inherited.insert(name.clone());
+        }
+        self.bind(name, value);
+    }
+
+    /// Declare names public in the module being loaded.
+    /// By default, all names defined by a module are public.
+    pub fn export(&mut self, names: &[String]) -> Result<(), Error> {
+        match self.module_scopes.last_mut() {
+            Some(scope) => {
+                scope
+                    .exports
+                    .get_or_insert_with(Vec::new)
+                    .extend(names.iter().cloned());
+                Ok(())
+            },
+            None => Err(Error::ExportOutsideModule),
+        }
+    }
+
+    /// Check if a name is a hidden name storing a module binding.
+    /// Such names cannot be written in source code.
+    pub fn is_hidden(name: &str) -> bool {
+        module::is_hidden(name)
+    }
+
+    /// Find and execute the source of a module
+    fn load_module(&mut self, name: &str) -> Result<(), Error> {
+        let file_name = format!("{}.mls", name);
+        if let Some(module) = stdlib::find(name) {
+            return match self.stdlib_dir.clone() {
+                Some(dir) => self.execute_file(dir.join(&file_name)),
+                None => {
+                    let origin = format!("<stdlib>/{}", file_name);
+                    self.execute_source_from(module.source.to_owned(), Some(&origin))
+                },
+            };
+        }
+
+        for dir in self.module_dirs.clone() {
+            let path = dir.join(&file_name);
+            if path.is_file() {
+                return self.execute_file(path);
+            }
+        }
+        Err(Error::UnknownModule(name.to_owned()))
     }
 
     /// Set debug printing on or off
@@ -46,6 +410,54 @@ impl Interpreter {
         self.debug_print = v;
     }
 
+    /// Limit the number of evaluation steps, after which evaluation fails with `OutOfFuel`.
+    /// `None` removes the limit.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Evaluation steps left, if limited
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Evaluation steps taken since the interpreter was created or `reset_steps` was called
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Start counting steps from zero
+    pub fn reset_steps(&mut self) {
+        self.steps = 0;
+    }
+
+    /// Token for cancelling evaluation from another thread, fails it with `Cancelled`.
+    /// Clones of the interpreter have tokens of their own.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Fail evaluation with `DeadlineExceeded` after the given time. `None` removes the deadline.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Send program output, e.g. from `println`, to a writer instead of stdout.
+    /// Use an `OutputBuffer` to capture it in memory.
+    pub fn set_output<W: Write + Send + 'static>(&mut self, writer: W) {
+        self.output = Output::new(writer);
+    }
+
+    /// Writer for program output
+    pub(crate) fn output(&self) -> MutexGuard<'_, dyn Write + Send + 'static> {
+        self.output.writer()
+    }
+
+    /// All current bindings, including builtins
+    pub fn namespace(&self) -> &HashMap<String, Value> {
+        &self.namespace
+    }
+
     /// Namespace bind
     pub fn bind(&mut self, name: String, value: Value) {
         self.namespace.insert(name, value);
@@ -58,181 +470,474 @@ impl Interpreter {
 
     /// Symbol name resolution
     #[must_use]
-    pub fn resolve(&self, name: &str) -> Result<Value, String> {
+    pub fn resolve(&self, name: &str) -> Result<Value, Error> {
         self.namespace
             .get(name)
             .cloned()
-            .ok_or(format!("Resolution failed '{:?}'", name))
+            .ok_or_else(|| Error::UnboundIdentifier(name.to_owned()))
+    }
+
+    /// Symbol name resolution in the scope of the code being evaluated, which sees the names
+    /// captured by the closure it's in, and the private names and imports of its module
+    pub(crate) fn resolve_here(&self, name: &str) -> Result<Value, Error> {
+        self.resolve_in(self.scope.as_deref(), name)
+    }
+
+    /// Symbol name resolution in a scope, or outside modules and closures with `None`
+    pub(crate) fn resolve_in(&self, scope: Option<&Scope>, name: &str) -> Result<Value, Error> {
+        if let Some(value) = scope.and_then(|s| s.lookup(name)) {
+            return Ok(value.clone());
+        }
+        self.namespace
+            .get(self.name_in(scope, name))
+            .cloned()
+            .ok_or_else(|| Error::UnboundIdentifier(name.to_owned()))
+    }
+
+    /// Name a binding has in the namespace, as seen by code of the module of a scope
+    pub(crate) fn name_in<'a>(&'a self, scope: Option<&Scope>, name: &'a str) -> &'a str {
+        let env = scope.and_then(Scope::module).and_then(|m| self.module_envs.get(m));
+        env.and_then(|e| e.get(name)).map_or(name, String::as_str)
+    }
+
+    /// Scope of the code being evaluated, if any. Lambdas are made and code is quoted in it.
+    pub(crate) fn current_scope(&self) -> Option<Arc<Scope>> {
+        self.scope.clone()
+    }
+
+    /// Evaluate the rest of the current evaluation in a scope, like unquoted code of a quote
+    /// made there. The scope is switched back when the evaluation is done.
+    pub(crate) fn enter_scope(&mut self, scope: Option<Arc<Scope>>) {
+        self.scope = scope;
     }
 
     /// Check if an identifier is stop-idfr, e.q. self-referencing
     #[must_use]
-    pub fn is_stop_idfr(&self, name: &str) -> Result<bool, String> {
-        Ok(self.resolve(name)? == Value::Quot(box Value::Idfr(name.to_owned())))
+    pub fn is_stop_idfr(&self, name: &str) -> Result<bool, Error> {
+        Ok(self.resolve_here(name)? == Value::Quot(box Value::Idfr(name.to_owned()), None))
     }
 
     /// Read file and execute contents
     #[must_use]
-    pub fn execute_file<P: AsRef<Path> + Debug>(&mut self, path: P) -> Result<(), String> {
+    pub fn execute_file<P: AsRef<Path> + Debug>(&mut self, path: P) -> Result<(), Error> {
         if self.debug_print {
             println!("{}EXECUTING FILE: {:?}", " ".repeat(self.exec_depth * 2), path);
         }
-        let source = fs::read_to_string(path).expect("Could not read file");
+        let source = fs::read_to_string(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.as_ref().display(), e)))?;
 
-        self.execute_source(source)
+        self.execute_source_from(source, Some(&path.as_ref().display().to_string()))
     }
 
     /// Execute source code text
     #[must_use]
-    pub fn execute_source(&mut self, source: String) -> Result<(), String> {
-        let mut tokens = parser::split_tokens(source).unwrap();
+    pub fn execute_source(&mut self, source: String) -> Result<(), Error> {
+        self.execute_source_from(source, None)
+    }
+
+    /// Execute source code text, prefixing error locations with the origin name if any
+    fn execute_source_from(&mut self, source: String, origin: Option<&str>) -> Result<(), Error> {
+        self.run(|intp| intp.execute_tokens(source, origin))
+    }
+
+    /// Parse and execute source code text one expression at a time
+    fn execute_tokens(&mut self, source: String, origin: Option<&str>) -> Result<(), Error> {
+        let in_file = |e: Error| match origin {
+            Some(name) => e.in_file(name),
+            None => e,
+        };
+
+        let origin_arc: Option<Arc<str>> = origin.map(Arc::from);
+        let mut tokens = parser::split_tokens(source).map_err(in_file)?;
         while !tokens.is_empty() {
-            let (exprt, newt) = parser::take_expr(tokens).expect("Invalid Expression");
+            let (exprt, newt) = parser::take_expr(tokens).map_err(in_file)?;
             tokens = newt;
-            self.execute(Value::parse(exprt)?)?;
+            let (value, spans) =
+                Value::parse_from(exprt, origin_arc.as_ref(), self.max_nesting).map_err(in_file)?;
+            self.eval(value, Frame::default()).map_err(|e| {
+                // Errors in expressions are located already, this is for plain values
+                if e.is_located() {
+                    e
+                } else {
+                    e.at(origin, spans.span)
+                }
+            })?;
         }
         Ok(())
     }
 
+    /// Parse a single value from tokens, like `Value::parse`, but allowing it to nest as deeply as
+    /// `max_nesting` does
+    pub fn parse(&self, tokens: Vec<parser::SpannedToken>) -> Result<Value, Error> {
+        Ok(Value::parse_from(tokens, None, self.max_nesting)?.0)
+    }
+
     /// Execute a value
     #[must_use]
-    pub fn execute(&mut self, mut value: Value) -> Result<Value, String> {
+    pub fn execute(&mut self, value: Value) -> Result<Value, Error> {
+        self.run(|intp| intp.eval(value, Frame::default()))
+    }
+
+    /// Run a public entry point, unless one is running already, so that cancellation requests
+    /// made during it are for it, and requests it didn't notice are dropped when it finishes
+    fn run<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, Error>) -> Result<T, Error> {
+        if self.running {
+            return f(self);
+        }
+        self.running = true;
+        self.cancel.start_run();
+        let result = f(self);
+        self.running = false;
+        self.cancel.finish_run();
+        result
+    }
+
+    /// Evaluate a value to normal form, with parameters of the lambda being applied bound in the frame.
+    /// Evaluating in a frame gives the same result as substituting the parameters first.
+    fn eval(&mut self, value: Value, frame: Frame) -> Result<Value, Error> {
         if self.debug_print {
             println!("{}EXEC: {}", " ".repeat(self.exec_depth * 2), value);
         }
+        if self.exec_depth >= self.max_depth {
+            let error = Error::RecursionLimit {
+                depth: self.exec_depth,
+                value,
+            };
+            return Err(self.traced(error, None));
+        }
 
         self.exec_depth += 1;
-        loop {
-            let oldv = value.clone();
-            value = self.execute_step(value)?;
-            if value == oldv {
-                break;
-            }
-        }
+        let stack_base = self.call_stack.len();
+        let scope = self.scope.clone();
+        let result = self
+            .eval_loop(value, frame)
+            .and_then(|v| self.check_nesting(v))
+            .map_err(|e| self.traced(e, None));
+        self.scope = scope;
+        self.call_stack.truncate(stack_base);
         self.exec_depth -= 1;
+        let value = result?;
         if self.debug_print {
             println!("{}DONE: {}", " ".repeat(self.exec_depth * 2), value);
         }
         Ok(value)
     }
 
-    /// Do one reduction step on a value
-    #[must_use]
-    pub fn execute_step(&mut self, value: Value) -> Result<Value, String> {
-        if self.debug_print {
-            println!("{}EXEC s: {}", " ".repeat(self.exec_depth * 2), value);
-        }
-        match value {
-            Value::Idfr(name) => self.resolve(&name),
-            Value::Lmbd(params, box body) if params.is_empty() => {
-                if let Value::Quot(box q) = body {
-                    Ok(q)
-                } else if let Value::Lmbd(a, box b) = body {
-                    Ok(Value::Lmbd(a, box b))
-                } else {
-                    Err("Lambda body must be quoted or another lambda".to_owned())
-                }
-            },
-            Value::Expr(args) => {
-                if args.len() == 1 {
-                    Ok(args[0].clone())
-                } else {
-                    match args[0].clone() {
-                        Value::Expr(a) => {
-                            let na = self.execute(Value::Expr(a))?;
-                            let mut newargs = vec![na];
-                            newargs.extend_from_slice(&args[1..]);
-                            Ok(Value::Expr(newargs))
+    /// Reduce a value until it's in normal form.
+    /// Values in tail position are reduced in the same loop, so that they don't grow the native stack.
+    /// Call stack frames pushed here are popped by `eval` when it's done, and so is the scope
+    /// switched to by applying a lambda or unquoting code.
+    fn eval_loop(&mut self, mut value: Value, mut frame: Frame) -> Result<Value, Error> {
+        let stack_base = self.call_stack.len();
+        loop {
+            self.step()?;
+            match value {
+                Value::Idfr(name) => {
+                    if let Some(v) = lookup(&frame, &name) {
+                        return Ok(v.clone());
+                    }
+                    value = self.resolve_here(&name)?;
+                    frame = Frame::default();
+                },
+                Value::Quot(q, scope) => return self.quote(q.substitute(&frame), scope),
+                Value::Lmbd(params, box body, scope) => {
+                    if !params.is_empty() {
+                        return Ok(Value::Lmbd(params, box body, scope).substitute(&frame));
+                    }
+                    value = unquote_body(body)?;
+                    self.scope = scope;
+                },
+                Value::Expr(list) => {
+                    let shared_location = list.shared_location();
+                    let location = shared_location.as_deref();
+                    let mut args = list.into_vec();
+                    if args.is_empty() {
+                        // Parsing makes Unit of `()`, but embedders can make empty expressions
+                        return Ok(Value::Unit);
+                    }
+                    if args.len() == 1 {
+                        value = args.pop().expect("Invalid state");
+                        continue;
+                    }
+                    let rest = args.split_off(1);
+                    let (head, callee) = self
+                        .eval_head(args.pop().expect("Invalid state"), &frame)
+                        .map_err(|e| locate(e, location, Some(0)))?;
+                    match head {
+                        Value::Bltn(name) => {
+                            value = self.call_builtin(name, rest, &frame, location)?;
+                            frame = Frame::default();
                         },
-                        Value::Idfr(idfr) => {
-                            let na = self.resolve(&idfr)?;
-                            let mut newargs = vec![na];
-                            newargs.extend_from_slice(&args[1..]);
-                            Ok(Value::Expr(newargs))
+                        Value::Lmbd(params, body, scope) => {
+                            // A tail call replaces the frame of the caller, except the first one,
+                            // which shows where this evaluation was called from
+                            let name = callee.unwrap_or_else(|| "<lambda>".to_owned());
+                            self.call_stack.truncate(stack_base + 1);
+                            self.call_stack.push((name, shared_location.clone()));
+                            let (v, f) = self.apply_lambda(params, *body, scope, rest, &frame, location)?;
+                            value = v;
+                            frame = f;
                         },
-                        Value::Bltn(name) => {
-                            if name == "error" {
-                                Err(format!(
-                                    "Runtime Error: {}",
-                                    args[1..]
-                                        .iter()
-                                        .map(|a| format!("{}", a))
-                                        .collect::<Vec<_>>()
-                                        .join(" ")
-                                ))
-                            } else if name == "quote" {
-                                if args.len() == 2 {
-                                    Ok(Value::Quot(box args[1].clone()))
-                                } else {
-                                    Err("Arg count".to_owned())
-                                }
-                            } else {
-                                let args_e: Vec<Value> = args[1..]
-                                    .iter()
-                                    .cloned()
-                                    .map(|a| self.execute(a))
-                                    .collect::<Result<_, _>>()?;
-
-                                if self.debug_print {
-                                    println!(
-                                        "{}EXEC b: ({} {})",
-                                        " ".repeat(self.exec_depth * 2),
-                                        name,
-                                        args_e
-                                            .iter()
-                                            .map(|a| format!("{}", a))
-                                            .collect::<Vec<_>>()
-                                            .join(" ")
-                                    );
-                                }
-
-                                corelib::call(self, name, args_e)
-                            }
+                        Value::Quot(q, _) => {
+                            let error = Error::TypeMismatch(format!("Quote cannot be executed: {}", q));
+                            return Err(locate(error, location, None));
                         },
-                        Value::Lmbd(params, body) => {
-                            if params.is_empty() {
-                                if args.len() > 1 {
-                                    if let Value::Quot(box q) = *body {
-                                        Ok(Value::Expr(
-                                            vec![q]
-                                                .iter()
-                                                .chain(args[1..args.len()].iter())
-                                                .cloned()
-                                                .collect(),
-                                        ))
-                                    } else if let Value::Lmbd(a, box b) = *body {
-                                        Ok(Value::Expr(
-                                            vec![Value::Lmbd(a, box b)]
-                                                .iter()
-                                                .chain(args[1..args.len()].iter())
-                                                .cloned()
-                                                .collect(),
-                                        ))
-                                    } else {
-                                        Err("Lambda body must be quoted or another lambda".to_owned())
-                                    }
-                                } else {
-                                    Ok(*body)
-                                }
-                            } else {
-                                let sym = params[0].clone();
-                                let val = self.execute(args[1].clone())?;
-
-                                Ok(Value::Expr(
-                                    vec![Value::Lmbd(params[1..].to_vec(), box (*body).replace(&sym, val))]
-                                        .iter()
-                                        .chain(args[2..args.len()].iter())
-                                        .cloned()
-                                        .collect(),
-                                ))
-                            }
+                        other => {
+                            let mut newargs = vec![other];
+                            newargs.extend(rest.into_iter().map(|a| a.substitute(&frame)));
+                            return Ok(Value::Expr(newargs.into()));
                         },
-                        Value::Quot(q) => Err(format!("Quote cannot be executed: {}", q)),
-                        _ => Ok(Value::Expr(args)),
                     }
+                },
+                v => return Ok(v),
+            }
+        }
+    }
+
+    /// Count an evaluation step, failing if there's no fuel left or evaluation was interrupted
+    fn step(&mut self) -> Result<(), Error> {
+        if let Some(fuel) = self.fuel {
+            if fuel == 0 {
+                return Err(Error::OutOfFuel { steps: self.steps });
+            }
+            self.fuel = Some(fuel - 1);
+        }
+        self.steps += 1;
+        if self.steps & (INTERRUPT_CHECK_INTERVAL - 1) == 0 {
+            if self.cancel.take() {
+                return Err(Error::Cancelled);
+            }
+            if let Some(deadline) = self.deadline {
+                if Instant::now() >= deadline {
+                    return Err(Error::DeadlineExceeded);
                 }
+            }
+        }
+        Ok(())
+    }
+
+    /// Bind lambda parameters to arguments evaluated in the caller's frame.
+    /// Returns the body to evaluate next, and the frame to evaluate it in. The body is evaluated
+    /// in the scope of the lambda, which is switched to here.
+    fn apply_lambda(
+        &mut self, params: Vec<String>, body: Value, scope: Option<Arc<Scope>>, args: Vec<Value>,
+        frame: &Frame, location: Option<&Location>,
+    ) -> Result<(Value, Frame), Error> {
+        let mut bindings = Vec::new();
+        let mut params = params.into_iter();
+        let mut args = args.into_iter().enumerate();
+        for p in &mut params {
+            match args.next() {
+                Some((i, a)) => {
+                    let value = self
+                        .eval(a, frame.clone())
+                        .map_err(|e| locate(e, location, Some(i + 1)))?;
+                    bindings.push((p, value));
+                },
+                None => {
+                    // Partial application
+                    let mut remaining = vec![p];
+                    remaining.extend(params);
+                    let lambda = Value::Lmbd(remaining, box body.substitute(&bindings), scope);
+                    return Ok((lambda, Frame::default()));
+                },
+            }
+        }
+
+        let body = unquote_body(body).map_err(|e| locate(e, location, None))?;
+        let extra: Vec<Value> = args.map(|(_, a)| a).collect();
+        if extra.is_empty() {
+            self.scope = scope;
+            Ok((body, Frame::new(bindings)))
+        } else {
+            // Apply the result to the remaining arguments, which are the caller's code
+            let caller = mem::replace(&mut self.scope, scope);
+            let result = self.eval(body, Frame::new(bindings));
+            self.scope = caller;
+            let mut newargs = vec![result?];
+            newargs.extend(extra.into_iter().map(|a| a.substitute(frame)));
+            Ok((Value::Expr(newargs.into()), Frame::default()))
+        }
+    }
+
+    /// Evaluate the head of an application until it can be applied.
+    /// Also returns the name of the last identifier resolved, if any.
+    fn eval_head(&mut self, head: Value, frame: &Frame) -> Result<(Value, Option<String>), Error> {
+        let mut callee = None;
+        let mut head = match head {
+            Value::Idfr(name) => {
+                let value = match lookup(frame, &name) {
+                    Some(v) => v.clone(),
+                    None => self.resolve_here(&name)?,
+                };
+                callee = Some(name);
+                value
             },
-            v => Ok(v),
+            Value::Expr(_) => return Ok((self.eval(head, frame.clone())?, None)),
+            other => other.substitute(frame),
+        };
+        loop {
+            match head {
+                Value::Idfr(name) => {
+                    head = self.resolve_here(&name)?;
+                    callee = Some(name);
+                },
+                Value::Expr(_) => return Ok((self.eval(head, Frame::default())?, callee)),
+                other => return Ok((other, callee)),
+            }
+        }
+    }
+
+    /// Apply a builtin to unevaluated arguments, returning a value that still needs evaluation.
+    /// Errors are located at the application, or at the argument that failed to evaluate.
+    fn call_builtin(
+        &mut self, name: String, args: Vec<Value>, frame: &Frame, location: Option<&Location>,
+    ) -> Result<Value, Error> {
+        self.call_builtin_unlocated(name, args, frame, location)
+            .map_err(|e| locate(e, location, None))
+    }
+
+    /// Apply a builtin, locating only errors of evaluating the arguments
+    fn call_builtin_unlocated(
+        &mut self, name: String, args: Vec<Value>, frame: &Frame, location: Option<&Location>,
+    ) -> Result<Value, Error> {
+        let builtin = Some((name.as_str(), location));
+        if name == "error" {
+            let mut args: Vec<Value> = args.into_iter().map(|a| a.substitute(frame)).collect();
+            let payload = match args.len() {
+                0 => Value::Unit,
+                1 => args.pop().expect("Invalid state"),
+                _ => Value::Expr(args.into()),
+            };
+            return Err(self.traced(Error::UserError(payload), builtin));
+        }
+        if name == "assert" {
+            if args.len() != 1 {
+                return Err(self.traced(Error::arity(&name, 1, args.len()), builtin));
+            }
+            let expr = args[0].clone();
+            return match self.eval(expr.clone(), frame.clone()) {
+                Ok(Value::Unit) => Err(self.traced(Error::AssertionFailed(expr), builtin)),
+                Ok(v) => Ok(v),
+                Err(e) => Err(self.traced(locate(e, location, Some(1)), builtin)),
+            };
+        }
+        if name == "quote" {
+            return if args.len() == 1 {
+                self.quote(args[0].clone().substitute(frame), self.current_scope())
+                    .map_err(|e| self.traced(e, builtin))
+            } else {
+                Err(self.traced(Error::arity(&name, 1, args.len()), builtin))
+            };
+        }
+
+        let mut args_e = Vec::with_capacity(args.len());
+        for (i, a) in args.into_iter().enumerate() {
+            match self.eval(a, frame.clone()) {
+                Ok(v) => args_e.push(v),
+                Err(e) => return Err(self.traced(locate(e, location, Some(i + 1)), builtin)),
+            }
+        }
+
+        if self.debug_print {
+            println!(
+                "{}EXEC b: ({} {})",
+                " ".repeat(self.exec_depth * 2),
+                name,
+                args_e
+                    .iter()
+                    .map(|a| format!("{}", a))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
+        }
+
+        corelib::call(self, name.clone(), args_e).map_err(|e| self.traced(e, builtin))
+    }
+
+    /// Quote a value in a scope, failing if that nests it deeper than `max_nesting`
+    fn quote(&self, value: Value, scope: Option<Arc<Scope>>) -> Result<Value, Error> {
+        self.check_nesting(Value::Quot(box value, scope))
+    }
+
+    /// Fail with `NestingLimit` if a value made by evaluation is nested deeper than `max_nesting`.
+    /// Every result of evaluation is checked, so that values nested deeper can't be made, as
+    /// handling them could overflow the stack.
+    fn check_nesting(&self, value: Value) -> Result<Value, Error> {
+        let depth = value.depth();
+        if depth > self.max_nesting {
+            Err(Error::NestingLimit { depth })
+        } else {
+            Ok(value)
+        }
+    }
+
+    /// Attach the current call stack to an error, unless it already has one.
+    /// A builtin being applied when the error was raised, and where, is included as the
+    /// innermost frame.
+    fn traced(&self, error: Error, builtin: Option<(&str, Option<&Location>)>) -> Error {
+        if error.trace().is_some() {
+            return error;
+        }
+        let mut trace: Vec<TraceFrame> = self
+            .call_stack
+            .iter()
+            .map(|(name, location)| trace_frame(name, location.as_deref()))
+            .collect();
+        if let Some((name, location)) = builtin {
+            trace.push(trace_frame(name, location));
+        }
+        if trace.is_empty() {
+            error
+        } else {
+            Error::Traced {
+                trace,
+                error: Box::new(error),
+            }
         }
     }
 }
+
+/// Parameters bound by the lambda application being evaluated, in parameter order
+type Frame = Arc<Vec<(String, Value)>>;
+
+/// Code to evaluate when a lambda has all of its parameters bound
+fn unquote_body(body: Value) -> Result<Value, Error> {
+    match body {
+        Value::Quot(box q, _) => Ok(q),
+        Value::Lmbd(a, b, m) => Ok(Value::Lmbd(a, b, m)),
+        _ => Err(Error::mismatch("Lambda body must be quoted or another lambda")),
+    }
+}
+
+/// Value of a parameter in a frame
+fn lookup<'a>(frame: &'a Frame, name: &str) -> Option<&'a Value> {
+    frame.iter().find(|(n, _)| n == name).map(|(_, v)| v)
+}
+
+/// Call stack frame of applying a function by a name at a location
+fn trace_frame(name: &str, location: Option<&Location>) -> TraceFrame {
+    TraceFrame {
+        name: name.to_owned(),
+        site: location.map(|l| error::site(l.origin.as_deref(), l.span)),
+    }
+}
+
+/// Attach the location of an expression to an error raised evaluating it, unless the error
+/// is located already. With an item index, the location of that item is used instead.
+/// Errors of loading imported modules are located in the module, and get the import site here.
+fn locate(error: Error, location: Option<&Location>, item: Option<usize>) -> Error {
+    match location {
+        Some(location) => {
+            let span = item
+                .and_then(|i| location.items.get(i).cloned())
+                .unwrap_or(location.span);
+            if error.is_located() {
+                error.imported_at(location.origin.as_deref(), span)
+            } else {
+                error.at(location.origin.as_deref(), span)
+            }
+        },
+        None => error,
+    }
+}
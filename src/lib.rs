@@ -9,9 +9,16 @@
 #![feature(box_patterns)]
 
 mod corelib;
+mod error;
 mod interpreter;
+mod module;
+mod output;
 pub mod parser;
+mod stdlib;
+pub mod testing;
 mod value;
 
-pub use self::interpreter::Interpreter;
-pub use self::value::Value;
+pub use self::error::{Error, TraceFrame};
+pub use self::interpreter::{CancelToken, Interpreter};
+pub use self::output::OutputBuffer;
+pub use self::value::{List, Scope, Value};
@@ -1,15 +1,285 @@
 //! MiniLisp interpreter
 
+use std::env;
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+
+use rustyline::completion::{self, Completer};
 use rustyline::error::ReadlineError;
-use rustyline::Editor;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::{CompletionType, Config, Editor, Helper};
 
 use clap;
 
 use minilisp::parser;
+use minilisp::testing;
+use minilisp::Error;
 use minilisp::Interpreter;
 use minilisp::Value;
 
+/// Characters separating identifiers, for completion. Identifiers can follow the quote shorthand.
+const BREAK_CHARS: &[u8] = b" \t\n()'\"";
+
+/// Stack size of the thread running the interpreter, big enough for `MAX_DEPTH` and `MAX_NESTING`
+/// even in debug builds, which take about 8 KiB of stack per level of evaluation
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+/// Default maximum evaluation depth
+const MAX_DEPTH: usize = 10_000;
+
+/// Maximum nesting of values
+const MAX_NESTING: usize = 50_000;
+
+/// Line width used for pretty-printing values
+const PRETTY_WIDTH: usize = 80;
+
+/// REPL commands and their descriptions
+const COMMANDS: [(&str, &str); 7] = [
+    (":env", "list all bindings"),
+    (":show NAME", "pretty-print the value bound to a name"),
+    (":load FILE", "execute a source file"),
+    (":reset", "start over with a fresh interpreter"),
+    (":trace on|off", "toggle evaluation trace printing"),
+    (":help", "show this list"),
+    (":quit", "exit the REPL"),
+];
+
+/// Line editor helper completing names bound in the interpreter
+struct NameCompleter {
+    /// Bound names in sorted order, refreshed before reading each line
+    names: Vec<String>,
+}
+impl NameCompleter {
+    /// Update names from the interpreter namespace
+    fn refresh(&mut self, intp: &Interpreter) {
+        self.names = intp
+            .namespace()
+            .keys()
+            .filter(|n| !Interpreter::is_hidden(n))
+            .cloned()
+            .collect();
+        self.names.sort();
+    }
+}
+impl Completer for NameCompleter {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize) -> rustyline::Result<(usize, Vec<String>)> {
+        let (start, word) = completion::extract_word(line, pos, None, BREAK_CHARS);
+        let commands = COMMANDS
+            .iter()
+            .map(|(usage, _)| usage.split(' ').next().unwrap().to_owned());
+        let candidates = if start == 0 && word.starts_with(':') {
+            commands.filter(|c| c.starts_with(word)).collect()
+        } else {
+            self.names
+                .iter()
+                .filter(|n| n.starts_with(word))
+                .cloned()
+                .collect()
+        };
+        Ok((start, candidates))
+    }
+}
+impl Hinter for NameCompleter {
+    fn hint(&self, _line: &str, _pos: usize) -> Option<String> {
+        None
+    }
+}
+impl Highlighter for NameCompleter {}
+impl Helper for NameCompleter {}
+
+/// Print an error and its evaluation trace, if any
+fn print_error(e: &Error) {
+    println!("Error: {}", e);
+    let trace = e.trace_lines();
+    if !trace.is_empty() {
+        println!("Trace (innermost last):");
+        for line in trace {
+            println!("  {}", line);
+        }
+    }
+}
+
+/// History file location, from `$MINILISP_HISTORY` or under the home directory
+fn history_path() -> Option<PathBuf> {
+    match env::var_os("MINILISP_HISTORY") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".minilisp_history")),
+    }
+}
+
+/// Interpreter options given on the command line, also used by `:reset`
+#[derive(Debug, Clone)]
+struct Settings {
+    verbose: bool,
+    stdlib_dir: Option<String>,
+    prelude: Option<Vec<String>>,
+    module_dirs: Vec<String>,
+    max_depth: usize,
+    fuel: Option<u64>,
+}
+
+/// Interpreter with corelib and stdlib bound
+fn new_interpreter(settings: &Settings) -> Result<Interpreter, Error> {
+    let mut intp = Interpreter::new()
+        .max_depth(settings.max_depth)
+        .max_nesting(MAX_NESTING);
+    if let Some(dir) = &settings.stdlib_dir {
+        intp = intp.stdlib_dir(dir);
+    }
+    for dir in &settings.module_dirs {
+        intp = intp.module_dir(dir);
+    }
+    if let Some(modules) = &settings.prelude {
+        intp = intp.prelude(&modules.iter().map(String::as_str).collect::<Vec<_>>());
+    }
+    let mut intp = intp.try_init()?;
+    intp.set_debug_print(settings.verbose);
+    intp.set_fuel(settings.fuel);
+    Ok(intp)
+}
+
+/// Whether the REPL should keep running after a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CommandResult {
+    Continue,
+    Quit,
+}
+
+/// Handle a colon-prefixed REPL command
+fn run_command(intp: &mut Interpreter, line: &str, settings: &Settings) -> CommandResult {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        [":env"] => {
+            let mut bindings: Vec<_> = intp
+                .namespace()
+                .iter()
+                .filter(|(n, _)| !Interpreter::is_hidden(n))
+                .collect();
+            bindings.sort_by(|a, b| a.0.cmp(b.0));
+            for (name, value) in bindings {
+                let kind = match value {
+                    Value::Bltn(_) => "builtin",
+                    Value::Lmbd(..) => "lambda",
+                    _ => "value",
+                };
+                println!("{:<24} {}", name, kind);
+            }
+        },
+        [":show", name] => match intp.resolve(name) {
+            Ok(value) => println!("{}", value.format_pretty(PRETTY_WIDTH)),
+            Err(e) => print_error(&e),
+        },
+        [":load", path] => {
+            if let Err(e) = intp.execute_file(path) {
+                print_error(&e);
+            }
+        },
+        [":reset"] => match new_interpreter(settings) {
+            Ok(fresh) => *intp = fresh,
+            Err(e) => print_error(&e),
+        },
+        [":trace", "on"] => intp.set_debug_print(true),
+        [":trace", "off"] => intp.set_debug_print(false),
+        [":help"] => {
+            for (usage, description) in COMMANDS.iter() {
+                println!("{:<16} {}", usage, description);
+            }
+        },
+        [":quit"] => return CommandResult::Quit,
+        _ => println!("Unknown command '{}', see :help", line.trim()),
+    }
+    CommandResult::Continue
+}
+
+/// Check if an entry has unclosed expressions or strings, and continuation lines should be read
+fn needs_more_input(entry: &str) -> bool {
+    match parser::split_tokens(entry.to_owned()) {
+        Ok(tokens) => parser::is_incomplete(&tokens),
+        Err(e) => match e.root() {
+            Error::ParseError(message) => message == "Unterminated string",
+            _ => false,
+        },
+    }
+}
+
+/// Entry as a single line, for history that stores one line per entry. Newlines become spaces,
+/// or `\n` escapes inside strings, and comments are dropped.
+fn history_line(entry: &str) -> String {
+    let entry = entry.trim_end();
+    if !entry.contains('\n') {
+        return entry.to_owned();
+    }
+
+    let mut line = String::new();
+    let mut in_str = false;
+    let mut escaped = false;
+    let mut comment = false;
+    for c in entry.chars() {
+        if comment {
+            if c == '\n' {
+                comment = false;
+                line.push(' ');
+            }
+        } else if in_str {
+            if c == '\n' {
+                line.push_str("\\n");
+            } else {
+                line.push(c);
+            }
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_str = false;
+            }
+        } else {
+            match c {
+                '"' => {
+                    in_str = true;
+                    line.push(c);
+                },
+                '#' => comment = true,
+                '\n' => line.push(' '),
+                c => line.push(c),
+            }
+        }
+    }
+    line.trim_end().to_owned()
+}
+
+/// Execute all expressions in an entry, printing their values
+fn execute_entry(intp: &mut Interpreter, entry: String) -> Result<(), Error> {
+    let mut tokens = parser::split_tokens(entry)?;
+    while !tokens.is_empty() {
+        let (exprt, newt) = parser::take_expr(tokens)?;
+        tokens = newt;
+        let value = intp.parse(exprt)?;
+        let value = intp.execute(value)?;
+        println!("{}", value.format());
+    }
+    Ok(())
+}
+
 fn main() {
+    // Evaluation nests on the native stack, so run it on a thread with a stack of known size
+    let interpreter = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(run)
+        .expect("Could not start the interpreter thread");
+    if interpreter.join().is_err() {
+        process::exit(101);
+    }
+}
+
+/// Run the command line interface
+fn run() {
     // Parse arguments
     let matches = clap::App::new("minilisp")
         .version("0.1")
@@ -26,42 +296,174 @@ fn main() {
                 .multiple(true)
                 .help("Verbosity level"),
         )
+        .arg(
+            clap::Arg::with_name("stdlib")
+                .long("stdlib")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Load stdlib modules from a directory instead of the built-in ones"),
+        )
+        .arg(
+            clap::Arg::with_name("module-dir")
+                .short("I")
+                .long("module-dir")
+                .takes_value(true)
+                .value_name("DIR")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Directory to search for imported modules"),
+        )
+        .arg(
+            clap::Arg::with_name("prelude")
+                .long("prelude")
+                .takes_value(true)
+                .value_name("MODULES")
+                .use_delimiter(true)
+                .help("Comma-separated stdlib modules to load, by default all except testing"),
+        )
+        .arg(
+            clap::Arg::with_name("no-prelude")
+                .long("no-prelude")
+                .conflicts_with("prelude")
+                .help("Do not load any stdlib modules"),
+        )
+        .arg(
+            clap::Arg::with_name("max-depth")
+                .long("max-depth")
+                .takes_value(true)
+                .value_name("N")
+                .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Maximum evaluation depth, deeper recursion fails with an error [default: 10000]"),
+        )
+        .arg(
+            clap::Arg::with_name("fuel")
+                .long("fuel")
+                .takes_value(true)
+                .value_name("STEPS")
+                .validator(|v| v.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Maximum number of evaluation steps, after which evaluation fails"),
+        )
+        .arg(
+            clap::Arg::with_name("no-history")
+                .long("no-history")
+                .help("Do not load or save REPL history"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("test")
+                .about("Run tests defined with deftest")
+                .arg(
+                    clap::Arg::with_name("PATHS")
+                        .help("Test files, or directories to search for *_test.mls files")
+                        .multiple(true)
+                        .default_value("."),
+                ),
+        )
         .get_matches();
 
     // Interpreter initalization
-    let mut intp = Interpreter::new().init();
+    let settings = Settings {
+        verbose: matches.occurrences_of("v") > 0,
+        stdlib_dir: matches.value_of("stdlib").map(str::to_owned),
+        prelude: if matches.is_present("no-prelude") {
+            Some(Vec::new())
+        } else {
+            matches
+                .values_of("prelude")
+                .map(|modules| modules.map(str::to_owned).collect())
+        },
+        module_dirs: matches
+            .values_of("module-dir")
+            .map(|dirs| dirs.map(str::to_owned).collect())
+            .unwrap_or_default(),
+        max_depth: matches
+            .value_of("max-depth")
+            .map_or(MAX_DEPTH, |v| v.parse().expect("Validated")),
+        fuel: matches.value_of("fuel").map(|v| v.parse().expect("Validated")),
+    };
 
-    if matches.occurrences_of("v") > 0 {
-        intp.set_debug_print(true);
+    if let Some(test_matches) = matches.subcommand_matches("test") {
+        let paths: Vec<&str> = test_matches.values_of("PATHS").expect("Defaulted").collect();
+        let files = match testing::find_test_files(&paths) {
+            Ok(files) => files,
+            Err(e) => {
+                print_error(&e.into());
+                process::exit(1);
+            },
+        };
+        let summary = testing::run_tests(&files, || new_interpreter(&settings), &mut io::stdout())
+            .expect("Could not write test report");
+        process::exit(if summary.is_ok() { 0 } else { 1 });
     }
 
+    let mut intp = match new_interpreter(&settings) {
+        Ok(intp) => intp,
+        Err(e) => {
+            print_error(&e);
+            process::exit(1);
+        },
+    };
+
     if let Some(filename) = matches.value_of("SOURCE") {
         if let Err(e) = intp.execute_file(filename) {
-            println!("Error: {}", e);
+            print_error(&e);
         }
     } else {
-        let mut rl = Editor::<()>::new();
+        let config = Config::builder()
+            .completion_type(CompletionType::List)
+            .max_history_size(1000)
+            .build();
+        let mut rl = Editor::<NameCompleter>::with_config(config);
+        rl.set_helper(Some(NameCompleter { names: Vec::new() }));
+
+        let history = if matches.is_present("no-history") {
+            None
+        } else {
+            history_path()
+        };
+        if let Some(path) = &history {
+            if path.exists() {
+                if let Err(e) = rl.load_history(path) {
+                    println!("Could not load history from {}: {}", path.display(), e);
+                }
+            }
+        }
+
+        let mut entry = String::new();
         loop {
-            match rl.readline("> ") {
+            if let Some(helper) = rl.helper_mut() {
+                helper.refresh(&intp);
+            }
+
+            let prompt = if entry.is_empty() { "> " } else { ".. " };
+            match rl.readline(prompt) {
                 Ok(line) => {
-                    rl.add_history_entry(line.as_ref());
-
-                    let mut tokens = parser::split_tokens(line).unwrap();
-
-                    while !tokens.is_empty() {
-                        let (exprt, newt) = parser::take_expr(tokens).expect("Invalid Expression");
-                        tokens = newt;
-
-                        match Value::parse(exprt) {
-                            Ok(expr) => match intp.execute(expr) {
-                                Ok(v) => println!("{}", v.format()),
-                                Err(e) => println!("Error: {:?}", e),
-                            },
-                            Err(e) => {
-                                println!("Error: {:?}", e);
-                            },
+                    if entry.is_empty() && line.trim_start().starts_with(':') {
+                        rl.add_history_entry(line.as_ref());
+                        if run_command(&mut intp, &line, &settings) == CommandResult::Quit {
+                            break;
                         }
+                        continue;
+                    }
+
+                    entry.push_str(&line);
+                    entry.push('\n');
+                    if needs_more_input(&entry) {
+                        continue;
                     }
+
+                    // Recalled as one entry, not line by line
+                    let recalled = history_line(&entry);
+                    if !recalled.is_empty() {
+                        rl.add_history_entry(recalled);
+                    }
+
+                    if let Err(e) = execute_entry(&mut intp, mem::take(&mut entry)) {
+                        print_error(&e);
+                    }
+                },
+                Err(ReadlineError::Interrupted) if !entry.is_empty() => {
+                    // Cancel the partial entry
+                    entry.clear();
                 },
                 Err(ReadlineError::Interrupted) => {
                     println!("CTRL-C");
@@ -77,5 +479,11 @@ fn main() {
                 },
             }
         }
+
+        if let Some(path) = &history {
+            if let Err(e) = rl.save_history(path) {
+                println!("Could not save history to {}: {}", path.display(), e);
+            }
+        }
     }
 }
@@ -0,0 +1,56 @@
+//! Module namespaces
+//!
+//! Each module is executed in a namespace of its own. When loading is done, the bindings it
+//! made are stored under hidden names. Lambdas and quotes remember the scope they were made in,
+//! which includes the module, and names in their bodies and in unquoted code are resolved to the
+//! hidden names of that module when they're evaluated.
+//! Importing a module binds its exported names to the values stored under the hidden names.
+
+use std::collections::{HashMap, HashSet};
+
+use super::value::Value;
+
+/// Separator between module and binding names in hidden names.
+/// It starts a comment in source code, so hidden names cannot be written there.
+const HIDDEN_SEPARATOR: char = '#';
+
+/// Name that a module binding is stored under
+pub fn hidden_name(module: &str, name: &str) -> String {
+    format!("{}{}{}", module, HIDDEN_SEPARATOR, name)
+}
+
+/// Check if a name is a hidden name of a module binding
+pub fn is_hidden(name: &str) -> bool {
+    name.contains(HIDDEN_SEPARATOR)
+}
+
+/// Check if a binding is a symbol, i.e. a stop-idfr like `true`.
+/// Symbols are shared by all modules and never hidden.
+pub fn is_symbol(name: &str, value: &Value) -> bool {
+    match value {
+        Value::Quot(box Value::Idfr(n), _) => n == name,
+        _ => false,
+    }
+}
+
+/// Check if a binding is visible in the namespace of a module being loaded
+pub fn is_shared(name: &str, value: &Value) -> bool {
+    is_hidden(name) || is_symbol(name, value) || *value == Value::Bltn(name.to_owned())
+}
+
+/// State of a module being loaded
+#[derive(Debug, Clone)]
+pub struct ModuleScope {
+    /// Module name
+    pub name: String,
+    /// Names declared public with `export`, if any
+    pub exports: Option<Vec<String>>,
+    /// Imported names, and the hidden names they refer to
+    pub aliases: HashMap<String, String>,
+    /// Bindings made while loading other modules imported here
+    pub inherited: HashSet<String>,
+    /// Namespace the module started with
+    pub base: HashMap<String, Value>,
+    /// Namespace of the importer, restored when loading is done
+    pub outer: HashMap<String, Value>,
+}
@@ -0,0 +1,69 @@
+//! Output sinks
+//!
+//! Output builtins like `println` write to the sink owned by the interpreter, instead of
+//! the process stdout, so that embedders can capture or redirect program output.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Shared writer for interpreter output
+#[derive(Clone)]
+pub struct Output(Arc<Mutex<dyn Write + Send>>);
+impl Output {
+    /// Output to the given writer
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        Output(Arc::new(Mutex::new(writer)))
+    }
+
+    /// Lock the writer for writing. A panic while writing doesn't make it unusable.
+    pub fn writer(&self) -> MutexGuard<'_, dyn Write + Send + 'static> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+impl Default for Output {
+    fn default() -> Self {
+        Self::new(io::stdout())
+    }
+}
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Output")
+    }
+}
+
+/// In-memory output sink. Clones share the same buffer.
+#[derive(Debug, Clone, Default)]
+pub struct OutputBuffer(Arc<Mutex<Vec<u8>>>);
+impl OutputBuffer {
+    /// Create a new, empty buffer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Output written so far, with invalid UTF-8 replaced
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.bytes()).into_owned()
+    }
+
+    /// Output written so far, leaving the buffer empty
+    pub fn take(&self) -> String {
+        let bytes = mem::take(&mut *self.bytes());
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    fn bytes(&self) -> MutexGuard<'_, Vec<u8>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bytes().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
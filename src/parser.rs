@@ -1,5 +1,15 @@
 //! Parser
 
+use std::fmt;
+use std::sync::Arc;
+
+use super::error::Error;
+
+/// Deepest nesting of expressions and quotes accepted by the parser, unless the interpreter is
+/// configured otherwise with `Interpreter::max_nesting`. Values are still dropped, compared and
+/// printed recursively, and this keeps that within the stack of a spawned thread.
+pub const MAX_NESTING: usize = 1000;
+
 /// Language token
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Token {
@@ -7,8 +17,70 @@ pub enum Token {
     OpenParen,
     /// `)`
     CloseParen,
+    /// `'`, shorthand for quoting the next expression
+    Quote,
     /// Any non-paren word
     Symbol(String),
+    /// Integer literal, a word of digits with an optional minus sign
+    Int(i64),
+    /// String literal, with escapes already processed
+    Str(String),
+}
+
+/// Location of a piece of source code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    /// Byte offset of the first character
+    pub start: usize,
+    /// Byte offset after the last character
+    pub end: usize,
+    /// Line of the first character, starting from 1
+    pub line: usize,
+    /// Column of the first character, starting from 1
+    pub column: usize,
+}
+impl Span {
+    /// Span covering both this and a later span
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            end: other.end,
+            ..self
+        }
+    }
+}
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// Token and its location in the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpannedToken {
+    /// The token itself
+    pub token: Token,
+    /// Source location
+    pub span: Span,
+}
+
+/// Source locations of a parsed value, mirroring its tree structure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanTree {
+    /// Location of the whole node
+    pub span: Span,
+    /// Locations of the child nodes, in order, if the node is an expression
+    pub children: Vec<SpanTree>,
+}
+
+/// Source location of an expression, kept with it through evaluation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// File name, if the source came from a file
+    pub origin: Option<Arc<str>>,
+    /// Location of the whole expression
+    pub span: Span,
+    /// Locations of the items of the expression, in order
+    pub items: Vec<Span>,
 }
 
 /// State of the split state machine
@@ -20,13 +92,36 @@ enum SplitMode {
     Symbol,
     /// Comment, remove all until EOL
     Comment,
+    /// Inside a string literal
+    Str,
+    /// After a backslash in a string literal
+    StrEscape,
+    /// After `\u` in a string literal, expecting `{`
+    UnicodeStart,
+    /// Inside `\u{...}`, with the value and number of hex digits so far
+    Unicode(u32, usize),
 }
 
 /// Split source code to tokens, drops comments
-pub fn split_tokens(s: String) -> Result<Vec<Token>, ()> {
-    let mut tokens: Vec<Token> = Vec::new();
+pub fn split_tokens(s: String) -> Result<Vec<SpannedToken>, Error> {
+    let mut tokens: Vec<SpannedToken> = Vec::new();
     let mut mode = SplitMode::Start;
-    for c in s.chars() {
+    let mut line: usize = 1;
+    let mut column: usize = 1;
+    for (offset, c) in s.char_indices() {
+        let span = Span {
+            start: offset,
+            end: offset + c.len_utf8(),
+            line,
+            column,
+        };
+        if c == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+
         let (new_mode, new_token) = match mode {
             SplitMode::Start | SplitMode::Symbol => match c {
                 ' ' => (SplitMode::Start, None),
@@ -34,13 +129,17 @@ pub fn split_tokens(s: String) -> Result<Vec<Token>, ()> {
                 '(' => (SplitMode::Start, Some(Token::OpenParen)),
                 ')' => (SplitMode::Start, Some(Token::CloseParen)),
                 '#' => (SplitMode::Comment, None),
+                '"' => (SplitMode::Str, Some(Token::Str(String::new()))),
+                '\'' => (SplitMode::Start, Some(Token::Quote)),
                 _ => {
                     if mode == SplitMode::Start {
                         (SplitMode::Symbol, Some(Token::Symbol(c.to_string())))
                     } else {
                         // Append to previous symbol
-                        if let Token::Symbol(ref mut s) = tokens.last_mut().expect("Invalid state") {
+                        let last = tokens.last_mut().expect("Invalid state");
+                        if let Token::Symbol(ref mut s) = last.token {
                             s.push(c);
+                            last.span.end = span.end;
                         } else {
                             panic!("Invalid state");
                         }
@@ -52,31 +151,93 @@ pub fn split_tokens(s: String) -> Result<Vec<Token>, ()> {
                 '\n' => (SplitMode::Start, None),
                 _ => (SplitMode::Comment, None),
             },
+            SplitMode::Str | SplitMode::StrEscape | SplitMode::UnicodeStart | SplitMode::Unicode(_, _) => {
+                let last = tokens.last_mut().expect("Invalid state");
+                last.span.end = span.end;
+                let s = match last.token {
+                    Token::Str(ref mut s) => s,
+                    _ => panic!("Invalid state"),
+                };
+                let invalid_escape =
+                    || Error::ParseError("Invalid escape in string".to_owned()).at(None, span);
+                match (mode, c) {
+                    (SplitMode::Str, '"') => (SplitMode::Start, None),
+                    (SplitMode::Str, '\\') => (SplitMode::StrEscape, None),
+                    (SplitMode::Str, c) => {
+                        s.push(c);
+                        (SplitMode::Str, None)
+                    },
+                    (SplitMode::StrEscape, 'u') => (SplitMode::UnicodeStart, None),
+                    (SplitMode::StrEscape, c) => {
+                        s.push(match c {
+                            'n' => '\n',
+                            't' => '\t',
+                            '"' => '"',
+                            '\\' => '\\',
+                            _ => return Err(invalid_escape()),
+                        });
+                        (SplitMode::Str, None)
+                    },
+                    (SplitMode::UnicodeStart, '{') => (SplitMode::Unicode(0, 0), None),
+                    (SplitMode::Unicode(value, digits), '}') if digits > 0 => {
+                        s.push(std::char::from_u32(value).ok_or_else(invalid_escape)?);
+                        (SplitMode::Str, None)
+                    },
+                    (SplitMode::Unicode(value, digits), c) if digits < 6 && c.is_ascii_hexdigit() => {
+                        let digit = c.to_digit(16).expect("Invalid state");
+                        (SplitMode::Unicode(value * 16 + digit, digits + 1), None)
+                    },
+                    _ => return Err(invalid_escape()),
+                }
+            },
         };
 
         mode = new_mode;
         if let Some(token) = new_token {
-            tokens.push(token);
+            tokens.push(SpannedToken { token, span });
+        }
+    }
+
+    if mode != SplitMode::Start && mode != SplitMode::Symbol && mode != SplitMode::Comment {
+        let start = tokens.last().expect("Invalid state").span;
+        return Err(Error::ParseError("Unterminated string".to_owned()).at(None, start));
+    }
+
+    for t in &mut tokens {
+        if let Token::Symbol(s) = &t.token {
+            if is_int_literal(s) {
+                let value = s.parse().map_err(|_| {
+                    Error::ParseError(format!("Integer literal out of range: {}", s)).at(None, t.span)
+                })?;
+                t.token = Token::Int(value);
+            }
         }
     }
 
     Ok(tokens)
 }
 
+/// Check if a word is an integer literal
+fn is_int_literal(s: &str) -> bool {
+    let digits = s.trim_start_matches('-');
+    s.len() - digits.len() <= 1 && !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
 /// Split vector of tokens to a single expression and rest of the vector
-pub fn take_expr(tokens: Vec<Token>) -> Result<(Vec<Token>, Vec<Token>), String> {
+pub fn take_expr(tokens: Vec<SpannedToken>) -> Result<(Vec<SpannedToken>, Vec<SpannedToken>), Error> {
     if tokens.is_empty() {
         return Ok((Vec::new(), Vec::new()));
     }
 
-    match tokens.first().unwrap() {
+    let first = tokens.first().unwrap();
+    match first.token {
         Token::OpenParen => {
             let mut depth: usize = 1;
             let mut index: usize = 0;
             for (i, t) in tokens.iter().enumerate().skip(1) {
-                if t == &Token::OpenParen {
+                if t.token == Token::OpenParen {
                     depth += 1;
-                } else if t == &Token::CloseParen {
+                } else if t.token == Token::CloseParen {
                     depth -= 1;
                     if depth == 0 {
                         index = i + 1;
@@ -85,13 +246,44 @@ pub fn take_expr(tokens: Vec<Token>) -> Result<(Vec<Token>, Vec<Token>), String>
                 }
             }
             if depth > 0 {
-                Err("Unbalanced (end)".to_owned())
+                Err(Error::ParseError("Unbalanced (end)".to_owned()).at(None, first.span))
             } else {
                 debug_assert!(index > 0);
                 Ok((tokens[0..index].to_vec(), tokens[index..].to_vec()))
             }
         },
-        Token::CloseParen => Err("Unbalanced (start)".to_owned()),
-        other => Ok((vec![other.clone()], tokens[1..].to_vec())),
+        Token::CloseParen => Err(Error::ParseError("Unbalanced (start)".to_owned()).at(None, first.span)),
+        Token::Quote => {
+            // Quotes apply to the expression after them
+            let quotes = tokens.iter().take_while(|t| t.token == Token::Quote).count();
+            if quotes == tokens.len() {
+                return Err(Error::ParseError("Nothing to quote".to_owned()).at(None, first.span));
+            }
+            let (quoted, rest) = take_expr(tokens[quotes..].to_vec())?;
+            let mut expr = tokens[..quotes].to_vec();
+            expr.extend(quoted);
+            Ok((expr, rest))
+        },
+        _ => Ok((vec![first.clone()], tokens[1..].to_vec())),
+    }
+}
+
+/// Check if tokens end inside an unclosed expression, i.e. more input is needed to complete it
+pub fn is_incomplete(tokens: &[SpannedToken]) -> bool {
+    if tokens.last().map(|t| &t.token) == Some(&Token::Quote) {
+        return true;
+    }
+    let mut depth: usize = 0;
+    for t in tokens {
+        if t.token == Token::OpenParen {
+            depth += 1;
+        } else if t.token == Token::CloseParen {
+            if depth == 0 {
+                // Unbalanced (start), more input cannot fix this
+                return false;
+            }
+            depth -= 1;
+        }
     }
+    depth > 0
 }
@@ -0,0 +1,41 @@
+//! Standard library modules, compiled into the crate
+
+/// A stdlib module
+#[derive(Debug, Clone, Copy)]
+pub struct Module {
+    /// Module name, also the file name without extension
+    pub name: &'static str,
+    /// Source code
+    pub source: &'static str,
+    /// Whether the module is in the default prelude
+    pub prelude: bool,
+}
+
+/// All stdlib modules, in default load order
+pub const MODULES: [Module; 4] = [
+    Module {
+        name: "logic",
+        source: include_str!("stdlib/logic.mls"),
+        prelude: true,
+    },
+    Module {
+        name: "peano",
+        source: include_str!("stdlib/peano.mls"),
+        prelude: true,
+    },
+    Module {
+        name: "qexpr",
+        source: include_str!("stdlib/qexpr.mls"),
+        prelude: true,
+    },
+    Module {
+        name: "testing",
+        source: include_str!("stdlib/testing.mls"),
+        prelude: false,
+    },
+];
+
+/// Find a module by name
+pub fn find(name: &str) -> Option<&'static Module> {
+    MODULES.iter().find(|m| m.name == name)
+}
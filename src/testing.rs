@@ -0,0 +1,160 @@
+//! Test runner for tests defined with `deftest` of the `testing` stdlib module
+//!
+//! A test file is executed once to find its tests, and again for each test, so that every test
+//! runs in a fresh interpreter and can't affect the others.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::error::Error;
+use super::interpreter::Interpreter;
+use super::output::OutputBuffer;
+use super::value::Value;
+
+/// Prefix of the names `deftest` binds tests to
+pub const TEST_PREFIX: &str = "test:";
+
+/// Suffix of the names of test files found in directories
+pub const TEST_FILE_SUFFIX: &str = "_test.mls";
+
+/// Outcome of a test run
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+    /// Number of tests that passed
+    pub passed: usize,
+    /// Names of the failed tests, and of the files that failed to load, in run order
+    pub failed: Vec<String>,
+}
+impl Summary {
+    /// Whether nothing failed
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Collect test files from the given files and directories in sorted order. Directories are
+/// searched recursively for files named `*_test.mls`, skipping hidden directories and `target`.
+pub fn find_test_files<P: AsRef<Path>>(paths: &[P]) -> io::Result<Vec<PathBuf>> {
+    fn visit(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+        let mut entries = fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        for entry in entries {
+            let name = entry.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+            if entry.is_dir() {
+                if !name.starts_with('.') && name != "target" {
+                    visit(&entry, files)?;
+                }
+            } else if name.ends_with(TEST_FILE_SUFFIX) {
+                files.push(entry);
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    for path in paths {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{}: no such file or directory", path.display()),
+            ));
+        }
+        if path.is_dir() {
+            visit(path, &mut files)?;
+        } else {
+            files.push(path.to_owned());
+        }
+    }
+    Ok(files)
+}
+
+/// Interpreter with the `testing` module imported and a test file executed.
+/// Output is captured to the returned buffer.
+fn load_test_file<F>(new_interpreter: &F, path: &Path) -> Result<(Interpreter, OutputBuffer), Error>
+where
+    F: Fn() -> Result<Interpreter, Error>,
+{
+    let mut intp = new_interpreter()?;
+    let output = OutputBuffer::new();
+    intp.set_output(output.clone());
+    intp.import("testing")?;
+    intp.execute_file(path)?;
+    Ok((intp, output))
+}
+
+/// Run a single test in a fresh interpreter, returning its output if it fails
+fn run_test<F>(new_interpreter: &F, path: &Path, name: &str) -> Result<(), (Error, String)>
+where
+    F: Fn() -> Result<Interpreter, Error>,
+{
+    let (mut intp, output) = load_test_file(new_interpreter, path).map_err(|e| (e, String::new()))?;
+    let body = Value::Expr(vec![Value::Idfr("unquote".to_owned()), Value::Idfr(name.to_owned())].into());
+    intp.execute(body).map(|_| ()).map_err(|e| (e, output.take()))
+}
+
+/// Run the tests defined in the files, each in an interpreter made by `new_interpreter`.
+/// Progress, failures with their output and a summary are written to `report`.
+pub fn run_tests<F, W>(files: &[PathBuf], new_interpreter: F, report: &mut W) -> io::Result<Summary>
+where
+    F: Fn() -> Result<Interpreter, Error>,
+    W: Write,
+{
+    let mut summary = Summary::default();
+    let mut failures = Vec::new();
+    for path in files {
+        let mut names: Vec<String> = match load_test_file(&new_interpreter, path) {
+            Ok((intp, _)) => intp
+                .namespace()
+                .keys()
+                .filter(|n| n.starts_with(TEST_PREFIX))
+                .cloned()
+                .collect(),
+            Err(e) => {
+                writeln!(report, "{} ... FAILED", path.display())?;
+                failures.push((path.display().to_string(), e, String::new()));
+                continue;
+            },
+        };
+        names.sort();
+
+        for name in names {
+            let test = format!("{}::{}", path.display(), &name[TEST_PREFIX.len()..]);
+            match run_test(&new_interpreter, path, &name) {
+                Ok(()) => {
+                    writeln!(report, "{} ... ok", test)?;
+                    summary.passed += 1;
+                },
+                Err((e, output)) => {
+                    writeln!(report, "{} ... FAILED", test)?;
+                    failures.push((test, e, output));
+                },
+            }
+        }
+    }
+
+    for (test, e, output) in failures {
+        writeln!(report, "\n---- {} ----", test)?;
+        write!(report, "{}", output)?;
+        writeln!(report, "Error: {}", e)?;
+        let trace = e.trace_lines();
+        if !trace.is_empty() {
+            writeln!(report, "Trace (innermost last):")?;
+            for line in trace {
+                writeln!(report, "  {}", line)?;
+            }
+        }
+        summary.failed.push(test);
+    }
+    writeln!(
+        report,
+        "\ntest result: {}. {} passed; {} failed",
+        if summary.is_ok() { "ok" } else { "FAILED" },
+        summary.passed,
+        summary.failed.len()
+    )?;
+    Ok(summary)
+}
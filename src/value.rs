@@ -1,48 +1,110 @@
-use super::{parser, Interpreter};
+use super::module;
+use super::{parser, Error, Interpreter};
 use std::fmt;
+use std::iter::FromIterator;
+use std::ops::Deref;
+use std::slice;
+use std::sync::Arc;
+use std::vec;
 
 /// A concrete run-time value
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone)]
 pub enum Value {
     /// Unit type (empty tuple)
     Unit,
     /// Identifier
     Idfr(String),
     /// Expression
-    /// Empty expressions resolve to the unit type, and is not allowed here
-    Expr(Vec<Value>),
+    /// Empty expressions are parsed as the unit type, and evaluate to it if made otherwise
+    Expr(List),
     /// Builtin function (a black box)
     Bltn(String),
-    /// No-evaluate marker
-    Quot(Box<Value>),
-    /// Lambda (parameter substitution)
-    Lmbd(Vec<String>, Box<Value>),
+    /// No-evaluate marker, with the scope it was quoted in, if any.
+    /// Names in the quoted code are resolved in that scope when it's unquoted.
+    /// The scope isn't compared in equality, so quoted data is equal wherever it was made.
+    Quot(Box<Value>, Option<Arc<Scope>>),
+    /// Lambda (parameter substitution), with the scope it was made in, if any.
+    /// Names in its body are resolved in that scope.
+    Lmbd(Vec<String>, Box<Value>, Option<Arc<Scope>>),
+    /// Integer
+    Int(i64),
+    /// String
+    Str(String),
 }
 impl Value {
-    /// Expects a single value (expression) already checked syntactically valid
-    pub fn parse(mut tokens: Vec<parser::Token>) -> Result<Self, String> {
-        assert!(!tokens.is_empty());
-        if tokens[0] == parser::Token::OpenParen {
-            let mut args = Vec::new();
-            tokens = tokens[1..tokens.len() - 1].to_vec();
-            while !tokens.is_empty() {
-                let (t, ts) = parser::take_expr(tokens).unwrap();
-                tokens = ts;
-                args.push(Self::parse(t)?);
-            }
-            if args.is_empty() {
-                Ok(Value::Unit)
-            } else {
-                Ok(Value::Expr(args))
+    /// Expects a single value (expression) already checked syntactically valid.
+    /// Nesting deeper than `parser::MAX_NESTING` is a parse error.
+    pub fn parse(tokens: Vec<parser::SpannedToken>) -> Result<Self, Error> {
+        Ok(Self::parse_spanned(tokens)?.0)
+    }
+
+    /// Like `parse`, but also returns the source location of each node
+    pub fn parse_spanned(tokens: Vec<parser::SpannedToken>) -> Result<(Self, parser::SpanTree), Error> {
+        Self::parse_from(tokens, None, parser::MAX_NESTING)
+    }
+
+    /// Like `parse_spanned`, with the file name the tokens came from and the deepest nesting allowed.
+    /// Expressions keep their locations, so that errors raised evaluating them can point to them.
+    /// Nesting is tracked on the heap, so that deeply nested input can't overflow the stack.
+    pub(crate) fn parse_from(
+        tokens: Vec<parser::SpannedToken>, origin: Option<&Arc<str>>, max_nesting: usize,
+    ) -> Result<(Self, parser::SpanTree), Error> {
+        let first = match tokens.first() {
+            Some(t) => t.span,
+            None => return Err(Error::ParseError("Empty expression".to_owned())),
+        };
+        let mut open: Vec<OpenNode> = Vec::new();
+        let mut result = None;
+        for t in tokens {
+            if result.is_some() {
+                return Err(Error::ParseError("Multiple values given as one".to_owned()).at(None, t.span));
             }
-        } else {
-            assert_eq!(tokens.len(), 1);
-            if let parser::Token::Symbol(sym) = tokens[0].clone() {
-                Ok(Value::Idfr(sym))
-            } else {
-                panic!("Invalid data passed to value");
+            let leaf = parser::SpanTree {
+                span: t.span,
+                children: Vec::new(),
+            };
+            let mut done = match t.token {
+                parser::Token::Quote | parser::Token::OpenParen => {
+                    if open.len() >= max_nesting {
+                        let message = format!("Nesting deeper than {} levels", max_nesting);
+                        return Err(Error::ParseError(message).at(None, t.span));
+                    }
+                    open.push(OpenNode {
+                        quote: t.token == parser::Token::Quote,
+                        start: t.span,
+                        items: Vec::new(),
+                        children: Vec::new(),
+                    });
+                    None
+                },
+                parser::Token::CloseParen => match open.pop() {
+                    Some(node) if !node.quote => Some(node.close(t.span, origin)),
+                    _ => return Err(Error::ParseError("Unbalanced (start)".to_owned()).at(None, t.span)),
+                },
+                parser::Token::Symbol(sym) => Some((Value::Idfr(sym), leaf)),
+                parser::Token::Int(i) => Some((Value::Int(i), leaf)),
+                parser::Token::Str(s) => Some((Value::Str(s), leaf)),
+            };
+            // Add the finished value to its parent, which finishes the parent if it's a quote
+            while let Some((value, tree)) = done.take() {
+                match open.last_mut() {
+                    None => result = Some((value, tree)),
+                    Some(node) => {
+                        let span = tree.span;
+                        node.items.push(value);
+                        node.children.push(tree);
+                        if node.quote {
+                            done = open.pop().map(|node| node.close(span, origin));
+                        }
+                    },
+                }
             }
         }
+        match (result, open.last()) {
+            (Some(result), None) => Ok(result),
+            (_, Some(node)) if node.quote => Err(Error::ParseError("Nothing to quote".to_owned()).at(None, node.start)),
+            _ => Err(Error::ParseError("Unbalanced (end)".to_owned()).at(None, first)),
+        }
     }
 
     /// Substitute symbol (identifier) with a value
@@ -52,60 +114,415 @@ impl Value {
             Value::Idfr(n) if n == sym => val.clone(),
             Value::Idfr(n) => Value::Idfr(n),
             Value::Bltn(n) => Value::Bltn(n),
-            Value::Quot(q) => Value::Quot(box q.replace(sym, val)),
-            Value::Expr(e) => Value::Expr(
-                e.into_iter()
-                    .map(|q| q.replace(sym, val.clone()))
-                    .collect::<Vec<_>>(),
-            ),
-            Value::Lmbd(a, b) => {
+            Value::Int(i) => Value::Int(i),
+            Value::Str(s) => Value::Str(s),
+            Value::Quot(q, m) => Value::Quot(box q.replace(sym, val), m),
+            Value::Expr(e) => Value::Expr(e.map(|q| q.replace(sym, val.clone()))),
+            Value::Lmbd(a, b, m) => {
                 // skip shadowed parameters
                 if a.contains(&sym.to_owned()) {
-                    Value::Lmbd(a, b)
+                    Value::Lmbd(a, b, m)
+                } else {
+                    Value::Lmbd(a, box b.replace(sym, val), m)
+                }
+            },
+        }
+    }
+
+    /// Substitute identifiers with values, except where shadowed by lambda parameters.
+    /// If a name is bound more than once, the first binding is used.
+    pub fn substitute(self, bindings: &[(String, Value)]) -> Self {
+        if bindings.is_empty() {
+            return self;
+        }
+        match self {
+            Value::Idfr(n) => match bindings.iter().find(|(b, _)| *b == n) {
+                Some((_, v)) => v.clone(),
+                None => Value::Idfr(n),
+            },
+            Value::Quot(q, m) => Value::Quot(box q.substitute(bindings), m),
+            Value::Expr(e) => Value::Expr(e.map(|q| q.substitute(bindings))),
+            Value::Lmbd(a, b, m) => {
+                if bindings.iter().any(|(n, _)| a.contains(n)) {
+                    let unshadowed: Vec<_> =
+                        bindings.iter().filter(|(n, _)| !a.contains(n)).cloned().collect();
+                    Value::Lmbd(a, box b.substitute(&unshadowed), m)
                 } else {
-                    Value::Lmbd(a, box b.replace(sym, val))
+                    Value::Lmbd(a, box b.substitute(bindings), m)
                 }
             },
+            other => other,
         }
     }
 
-    /// Recursively resolve all identifiers until a stop-idfr is reached
+    /// Add the names of identifiers anywhere in the value, quoted data included, that aren't
+    /// in the list yet
+    pub(crate) fn collect_names(&self, names: &mut Vec<String>) {
+        match self {
+            Value::Idfr(n) => {
+                if !names.contains(n) {
+                    names.push(n.clone());
+                }
+            },
+            Value::Quot(q, _) | Value::Lmbd(_, q, _) => q.collect_names(names),
+            Value::Expr(e) => e.iter().for_each(|v| v.collect_names(names)),
+            _ => {},
+        }
+    }
+
+    /// Recursively resolve all identifiers until a stop-idfr is reached.
+    /// Names in quotes are resolved in the scope they were quoted in.
     #[must_use]
-    pub fn resolve_all(self, intp: &Interpreter) -> Result<Self, String> {
+    pub fn resolve_all(self, intp: &Interpreter) -> Result<Self, Error> {
+        self.resolve_all_in(intp, intp.current_scope().as_deref())
+    }
+
+    fn resolve_all_in(self, intp: &Interpreter, scope: Option<&Scope>) -> Result<Self, Error> {
         match self {
             Value::Unit => Ok(Value::Unit),
             Value::Idfr(n) => {
-                if intp.is_stop_idfr(&n)? {
+                let value = intp.resolve_in(scope, &n)?;
+                if module::is_symbol(&n, &value) {
                     Ok(Value::Idfr(n))
                 } else {
-                    intp.resolve(&n)?.resolve_all(intp)
+                    value.resolve_all_in(intp, scope)
                 }
             },
             Value::Bltn(n) => Ok(Value::Bltn(n)),
-            Value::Quot(q) => Ok(Value::Quot(box q.resolve_all(intp)?)),
+            Value::Int(i) => Ok(Value::Int(i)),
+            Value::Str(s) => Ok(Value::Str(s)),
+            Value::Quot(q, m) => Ok(Value::Quot(box q.resolve_all_in(intp, m.as_deref())?, m)),
             Value::Expr(e) => Ok(Value::Expr(
                 e.into_iter()
-                    .map(|q| q.resolve_all(intp))
-                    .collect::<Result<Vec<_>, String>>()?,
+                    .map(|q| q.resolve_all_in(intp, scope))
+                    .collect::<Result<List, Error>>()?,
             )),
-            Value::Lmbd(_, _) => unimplemented!(),
+            Value::Lmbd(a, b, m) => Ok(Value::Lmbd(a, b, m)),
+        }
+    }
+
+    /// The quoted value, if this is a `(quote x)` expression
+    fn quote_form(&self) -> Option<&Value> {
+        match self {
+            Value::Expr(e) if e.len() == 2 && e[0] == Value::Idfr("quote".to_owned()) => Some(&e[1]),
+            _ => None,
+        }
+    }
+
+    /// How many expressions, quotes and lambdas are nested in the deepest part of the value,
+    /// values captured in their scopes included.
+    /// Expressions and scopes store their depth, so only directly nested quotes and lambdas are walked.
+    pub fn depth(&self) -> usize {
+        let mut depth = 0;
+        let mut deepest = 0;
+        let mut value = self;
+        loop {
+            match value {
+                Value::Quot(inner, scope) | Value::Lmbd(_, inner, scope) => {
+                    depth += 1;
+                    if let Some(scope) = scope {
+                        deepest = deepest.max(depth + scope.depth);
+                    }
+                    value = inner;
+                },
+                Value::Expr(e) => return deepest.max(depth + e.depth),
+                _ => return deepest.max(depth),
+            }
         }
     }
 
     /// Human-readable form
     pub fn format(&self) -> String {
+        if let Some(q) = self.quote_form() {
+            return format!("'{}", q.format());
+        }
         match self {
             Value::Unit => "Unit".to_owned(),
             Value::Idfr(n) => format!(":{}", n.clone()),
             Value::Bltn(n) => format!("#{}", n.clone()),
-            Value::Quot(q) => format!("'{}", q.format()),
+            Value::Quot(q, _) => format!("'{}", q.format()),
             Value::Expr(e) => format!("({})", e.iter().map(Value::format).collect::<Vec<_>>().join(" ")),
-            Value::Lmbd(a, b) => format!("(\\ {} -> {})", a.join(" "), b.format()),
+            Value::Lmbd(a, b, _) => format!("(\\ {} -> {})", a.join(" "), b.format()),
+            Value::Int(i) => i.to_string(),
+            Value::Str(s) => format!("\"{}\"", escape(s)),
+        }
+    }
+
+    /// Human-readable form, broken to indented lines where it doesn't fit to the width
+    pub fn format_pretty(&self, width: usize) -> String {
+        self.format_indented(0, width)
+    }
+
+    /// Pretty-print with the first line starting at `indent` columns
+    fn format_indented(&self, indent: usize, width: usize) -> String {
+        let flat = self.format();
+        if indent + flat.len() <= width {
+            return flat;
+        }
+
+        if let Some(q) = self.quote_form() {
+            return format!("'{}", q.format_indented(indent + 1, width));
+        }
+        let pad = " ".repeat(indent + 2);
+        match self {
+            Value::Quot(q, _) => format!("'{}", q.format_indented(indent + 1, width)),
+            Value::Expr(e) if e.len() > 1 => format!(
+                "({}\n{}\n{})",
+                e[0].format_indented(indent + 1, width),
+                e[1..]
+                    .iter()
+                    .map(|v| format!("{}{}", pad, v.format_indented(indent + 2, width)))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                " ".repeat(indent)
+            ),
+            Value::Lmbd(a, b, _) => format!(
+                "(\\ {} ->\n{}{}\n{})",
+                a.join(" "),
+                pad,
+                b.format_indented(indent + 2, width),
+                " ".repeat(indent)
+            ),
+            _ => flat,
         }
     }
 }
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Unit, Value::Unit) => true,
+            (Value::Idfr(a), Value::Idfr(b)) | (Value::Bltn(a), Value::Bltn(b)) => a == b,
+            (Value::Expr(a), Value::Expr(b)) => a == b,
+            (Value::Quot(a, _), Value::Quot(b, _)) => a == b,
+            (Value::Lmbd(a, b, m), Value::Lmbd(c, d, n)) => a == c && b == d && m == n,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+impl Eq for Value {}
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.format())
     }
 }
+
+/// Where code was written: the module it belongs to, and the bindings captured by the closure
+/// it's in, if any. Names in the code are looked up in the captured bindings first, then in the
+/// namespace of the module.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Scope {
+    module: Option<Arc<str>>,
+    captured: Vec<(String, Value)>,
+    /// Nesting depth of the deepest captured value
+    depth: usize,
+}
+impl Scope {
+    /// Scope in a module, or outside modules with `None`, with the bindings captured by a closure
+    pub(crate) fn new(module: Option<Arc<str>>, captured: Vec<(String, Value)>) -> Self {
+        Self {
+            depth: captured.iter().map(|(_, v)| v.depth()).max().unwrap_or(0),
+            module,
+            captured,
+        }
+    }
+
+    /// Module the code belongs to, if any
+    pub fn module(&self) -> Option<&str> {
+        self.module.as_deref()
+    }
+
+    /// Module the code belongs to, shared
+    pub(crate) fn module_arc(&self) -> Option<Arc<str>> {
+        self.module.clone()
+    }
+
+    /// Names captured by a closure, and their values when it was created
+    pub fn captured(&self) -> &[(String, Value)] {
+        &self.captured
+    }
+
+    /// Captured value of a name
+    pub(crate) fn lookup(&self, name: &str) -> Option<&Value> {
+        self.captured.iter().find(|(n, _)| n == name).map(|(_, v)| v)
+    }
+}
+
+/// Expression or quote being parsed, waiting for the rest of its items
+struct OpenNode {
+    /// Whether this is a `'x` quote, finished by its first item, instead of a parenthesized expression
+    quote: bool,
+    /// Location of the opening token
+    start: parser::Span,
+    items: Vec<Value>,
+    children: Vec<parser::SpanTree>,
+}
+impl OpenNode {
+    /// Finished value and its locations, with the span of the last token
+    fn close(self, end: parser::Span, origin: Option<&Arc<str>>) -> (Value, parser::SpanTree) {
+        let span = self.start.to(end);
+        let mut children = self.children;
+        let mut items = self.items;
+        if self.quote {
+            // `'x` is the same as `(quote x)`
+            children.insert(0, parser::SpanTree {
+                span: self.start,
+                children: Vec::new(),
+            });
+            items.insert(0, Value::Idfr("quote".to_owned()));
+        }
+        let value = if items.is_empty() {
+            Value::Unit
+        } else {
+            let spans: Vec<parser::Span> = children.iter().map(|c| c.span).collect();
+            Value::Expr(List::located(items, located(origin, span, &spans)))
+        };
+        (value, parser::SpanTree { span, children })
+    }
+}
+
+/// Location of a parsed expression
+fn located(origin: Option<&Arc<str>>, span: parser::Span, items: &[parser::Span]) -> parser::Location {
+    parser::Location {
+        origin: origin.cloned(),
+        span,
+        items: items.to_vec(),
+    }
+}
+
+/// Nesting depth of an expression with the items
+fn depth_of(items: &[Value]) -> usize {
+    1 + items.iter().map(Value::depth).max().unwrap_or(0)
+}
+
+/// Escape a string so that it can be written as a string literal
+fn escape(s: &str) -> String {
+    let mut result = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            c if c.is_control() => result.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Items of an expression. Clones and tails share the storage instead of copying it.
+/// Parsed expressions also have their source location, which isn't compared in equality.
+#[derive(Clone)]
+pub struct List {
+    items: Arc<Vec<Value>>,
+    start: usize,
+    location: Option<Arc<parser::Location>>,
+    /// Nesting depth, see `Value::depth`. Tails keep the depth of the whole list.
+    depth: usize,
+}
+impl List {
+    /// Items of an expression parsed from the location
+    pub(crate) fn located(items: Vec<Value>, location: parser::Location) -> List {
+        List {
+            depth: depth_of(&items),
+            items: Arc::new(items),
+            start: 0,
+            location: Some(Arc::new(location)),
+        }
+    }
+
+    /// Source location, if the expression was parsed
+    pub fn location(&self) -> Option<&parser::Location> {
+        self.location.as_deref()
+    }
+
+    /// Source location, if the expression was parsed, shared
+    pub(crate) fn shared_location(&self) -> Option<Arc<parser::Location>> {
+        self.location.clone()
+    }
+
+    /// All but the first item
+    pub fn tail(&self) -> List {
+        List {
+            items: self.items.clone(),
+            start: (self.start + 1).min(self.items.len()),
+            location: None,
+            depth: self.depth,
+        }
+    }
+
+    /// Apply a function to each item, keeping the source location
+    pub fn map<F: FnMut(Value) -> Value>(self, f: F) -> List {
+        let location = self.location.clone();
+        let items: Vec<Value> = self.into_iter().map(f).collect();
+        List {
+            depth: depth_of(&items),
+            items: Arc::new(items),
+            start: 0,
+            location,
+        }
+    }
+
+    /// Items as a vector, copying them only if the storage is shared
+    pub fn into_vec(self) -> Vec<Value> {
+        if self.start == 0 {
+            match Arc::try_unwrap(self.items) {
+                Ok(items) => items,
+                Err(items) => items.to_vec(),
+            }
+        } else {
+            self.items[self.start..].to_vec()
+        }
+    }
+}
+impl Deref for List {
+    type Target = [Value];
+
+    fn deref(&self) -> &[Value] {
+        &self.items[self.start..]
+    }
+}
+impl PartialEq for List {
+    fn eq(&self, other: &List) -> bool {
+        **self == **other
+    }
+}
+impl Eq for List {}
+impl fmt::Debug for List {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+impl From<Vec<Value>> for List {
+    fn from(items: Vec<Value>) -> Self {
+        List {
+            depth: depth_of(&items),
+            items: Arc::new(items),
+            start: 0,
+            location: None,
+        }
+    }
+}
+impl FromIterator<Value> for List {
+    fn from_iter<I: IntoIterator<Item = Value>>(iter: I) -> Self {
+        List::from(iter.into_iter().collect::<Vec<_>>())
+    }
+}
+impl IntoIterator for List {
+    type IntoIter = vec::IntoIter<Value>;
+    type Item = Value;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.into_vec().into_iter()
+    }
+}
+impl<'a> IntoIterator for &'a List {
+    type IntoIter = slice::Iter<'a, Value>;
+    type Item = &'a Value;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
@@ -1,18 +1,801 @@
+use std::env;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use minilisp::Interpreter;
+use minilisp::{parser, testing, CancelToken, Error, Interpreter, List, OutputBuffer, Value};
 
-#[test]
-fn test_lang_suite() -> io::Result<()> {
-    for entry in fs::read_dir(Path::new("tests/langsuite/"))? {
+/// Error message followed by its trace, one frame per indented line
+fn error_report(e: &Error) -> String {
+    let mut report = e.to_string();
+    for line in e.trace_lines() {
+        report += &format!("\n  {}", line);
+    }
+    report
+}
+
+/// Run a suite file, checking its output and errors against golden files.
+/// Output printed by the program must match `NAME.out`, if it exists. Execution must succeed,
+/// unless `NAME.err` exists, in which case it must fail with that error message and trace.
+fn run_suite_file(path: &Path, intp: &mut Interpreter) -> Result<(), String> {
+    let output = OutputBuffer::new();
+    intp.set_output(output.clone());
+    let result = intp.execute_file(path);
+
+    let expected_err = path.with_extension("err");
+    match (result, expected_err.exists()) {
+        (Ok(()), false) => {},
+        (Ok(()), true) => return Err("Expected an error, but execution succeeded".to_owned()),
+        (Err(e), false) => return Err(error_report(&e)),
+        (Err(e), true) => {
+            let expected = fs::read_to_string(&expected_err).map_err(|e| e.to_string())?;
+            let got = error_report(&e);
+            if got != expected.trim_end() {
+                return Err(format!("Expected error:\n{}\nGot:\n{}", expected.trim_end(), got));
+            }
+        },
+    }
+
+    let expected_out = path.with_extension("out");
+    if expected_out.exists() {
+        let expected = fs::read_to_string(&expected_out).map_err(|e| e.to_string())?;
+        let got = output.take();
+        if got != expected {
+            return Err(format!("Expected output:\n{}\nGot:\n{}", expected, got));
+        }
+    }
+    Ok(())
+}
+
+/// Run a suite file with a fresh interpreter, failing the test if it fails.
+/// A failing file is run again with evaluation tracing, to show how it failed.
+fn run_suite_case(path: &str, new_interpreter: impl Fn() -> Interpreter) {
+    let path = Path::new(path);
+    if let Err(message) = run_suite_file(path, &mut new_interpreter()) {
+        println!("Trace of {}:", path.display());
+        let mut intp = new_interpreter();
+        intp.set_debug_print(true);
+        let _ = run_suite_file(path, &mut intp);
+        panic!("{} failed:\n{}", path.display(), message);
+    }
+}
+
+/// Names of the `.mls` files in a directory without the extension, sorted
+fn suite_files(dir: &str) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(Path::new(dir))? {
         let path = entry?.path();
         assert!(!path.is_dir());
+        if path.extension().and_then(|ext| ext.to_str()) == Some("mls") {
+            names.push(path.file_stem().unwrap().to_string_lossy().into_owned());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
 
-        let mut intp = Interpreter::new().init();
-        intp.set_debug_print(true);
-        intp.execute_file(&path).expect("Error");
+/// Define a test for each listed `.mls` file of a suite directory, in a module named after the suite.
+/// `test_suites_are_complete` checks that no file is left out.
+macro_rules! suite {
+    ($suite:ident, $dir:expr, $new_interpreter:expr, [$($file:ident),* $(,)*]) => {
+        mod $suite {
+            use super::*;
+
+            pub const DIR: &str = $dir;
+            pub const FILES: &[&str] = &[$(stringify!($file)),*];
+
+            $(
+                #[test]
+                fn $file() {
+                    run_suite_case(concat!($dir, "/", stringify!($file), ".mls"), $new_interpreter);
+                }
+            )*
+        }
+    };
+}
+
+suite!(
+    langsuite,
+    "tests/langsuite",
+    || Interpreter::new().module_dir("tests/modules").init(),
+    [
+        assert_failure,
+        core,
+        import_error,
+        ints,
+        lambda,
+        logic,
+        module_error,
+        modules,
+        peano,
+        printing,
+        quote_shorthand,
+        quoted_expr,
+        strings,
+        user_error,
+    ]
+);
+
+suite!(
+    kernelsuite,
+    "tests/kernelsuite",
+    || Interpreter::new().no_prelude().init(),
+    [builtins]
+);
+
+suite!(examples, "examples", || Interpreter::new().init(), [countdown]);
+
+#[test]
+fn test_suites_are_complete() -> io::Result<()> {
+    for &(dir, files) in &[
+        (langsuite::DIR, langsuite::FILES),
+        (kernelsuite::DIR, kernelsuite::FILES),
+        (examples::DIR, examples::FILES),
+    ] {
+        assert_eq!(
+            suite_files(dir)?,
+            files,
+            "Suite files of {} must be listed in suite!",
+            dir
+        );
     }
     Ok(())
 }
+
+#[test]
+fn test_import_cycle() {
+    let mut intp = Interpreter::new().module_dir("tests/modules").init();
+    match intp.import("cycle_a").unwrap_err().root() {
+        Error::ImportCycle(modules) => assert_eq!(modules, &["cycle_a", "cycle_b", "cycle_a"]),
+        other => panic!("Unexpected error: {}", other),
+    }
+}
+
+#[test]
+fn test_import_error_source() {
+    let mut intp = Interpreter::new().module_dir("tests/modules").init();
+    let error = intp.import("broken").unwrap_err();
+    assert!(matches!(error, Error::Importing { .. }));
+
+    // The source chain leads through the import to the error raised in the module
+    let mut source: &dyn std::error::Error = &error;
+    while let Some(inner) = source.source() {
+        source = inner;
+    }
+    assert_eq!(source.to_string(), "Integer arguments required");
+}
+
+#[test]
+fn test_import_arguments() {
+    let mut intp = Interpreter::new().init();
+    let result = intp.execute_source("(import 'peano 'n: 'extra)".to_owned());
+    let error = result.unwrap_err();
+    match error.root() {
+        Error::ArityMismatch { name, expected, got } => {
+            assert_eq!((name.as_str(), expected, *got), ("import", &(1..=2), 3))
+        },
+        other => panic!("Unexpected error: {}", other),
+    }
+    assert_eq!(error.to_string(), "1:1: Arg count: import takes 1 to 2 arguments, got 3");
+}
+
+#[test]
+fn test_arity_messages() {
+    let mut intp = Interpreter::new().init();
+    for &(source, message) in &[
+        ("(q:head '(1) '(2))", "1:1: Arg count: q:head takes 1 argument, got 2"),
+        ("(i:add 1)", "1:1: Arg count: i:add takes 2 arguments, got 1"),
+    ] {
+        let error = intp.execute_source(source.to_owned()).unwrap_err();
+        assert_eq!(error.to_string(), message);
+    }
+
+    // Builtins taking any number of arguments from a minimum up
+    let error = Error::ArityMismatch {
+        name: "lambda".to_owned(),
+        expected: 1..=usize::MAX,
+        got: 0,
+    };
+    assert_eq!(error.to_string(), "Arg count: lambda takes at least 1 argument, got 0");
+}
+
+#[test]
+fn test_error_kinds() {
+    let mut intp = Interpreter::new().init();
+    for source in &["(s:substring \"abc\" 2 4)", "(q:head '())"] {
+        match intp.execute_source((*source).to_owned()).unwrap_err().root() {
+            Error::OutOfRange(_) => {},
+            other => panic!("Unexpected error: {}", other),
+        }
+    }
+    match intp.execute_source("(s:split \"a,b\" \"\")".to_owned()).unwrap_err().root() {
+        Error::InvalidArgument(message) => assert_eq!(message, "Separator must not be empty"),
+        other => panic!("Unexpected error: {}", other),
+    }
+    match intp.execute_source("(export 'x)".to_owned()).unwrap_err().root() {
+        Error::ExportOutsideModule => {},
+        other => panic!("Unexpected error: {}", other),
+    }
+    match intp.execute_source("(s:length 1)".to_owned()).unwrap_err().root() {
+        Error::TypeMismatch(message) => assert_eq!(message, "String argument required"),
+        other => panic!("Unexpected error: {}", other),
+    }
+}
+
+#[test]
+fn test_module_private_names() {
+    let mut intp = Interpreter::new().module_dir("tests/modules").init();
+    intp.import("private").expect("Error");
+    assert!(intp.resolve("public_twice").is_ok());
+    assert!(intp.resolve("helper").is_err());
+    assert!(intp.resolve("p:mul_helper").is_err());
+    assert!(intp.resolve("p:mul").is_ok());
+
+    // Hidden names can't be reached by building symbols from strings
+    for source in &[
+        "(unquote (s:to_symbol \"peano#p:sub_nocheck\"))",
+        "(set (s:to_symbol \"peano#p:zero?\") (lambda 'x 'true))",
+        "(del (s:to_symbol \"peano#p:zero?\"))",
+    ] {
+        match intp.execute_source((*source).to_owned()).unwrap_err().root() {
+            Error::InvalidArgument(_) => {},
+            other => panic!("Unexpected error: {}", other),
+        }
+    }
+    assert!(intp
+        .execute_source("(assert (p:zero? (p:sub p:1 p:1)))".to_owned())
+        .is_ok());
+
+    // Code passed to module functions can't reach their private names
+    let result = intp.execute_source("(p:dec '(p:sub_nocheck p:5 p:2))".to_owned());
+    match result.unwrap_err().root() {
+        Error::UnboundIdentifier(name) => assert_eq!(name, "p:sub_nocheck"),
+        other => panic!("Unexpected error: {}", other),
+    }
+
+    // Code and traces show the names used in the module, not the hidden names
+    let code = intp.resolve("p:add").expect("Error").format();
+    assert!(!code.contains('#'), "{}", code);
+    intp.import("hof").expect("Error");
+    let error = intp.execute_source("(failing 1)".to_owned()).unwrap_err();
+    assert_eq!(error.trace_lines(), vec![
+        "in failing at 1:1",
+        "in run_twice at tests/modules/hof.mls:36:16",
+        "in i:add at tests/modules/hof.mls:36:34",
+    ]);
+}
+
+#[test]
+fn test_error_locations() {
+    let mut intp = Interpreter::new().init();
+    let source = "(set 'f (lambda 'n '(i:add n undefined)))\n(f 1)";
+    let error = intp.execute_source(source.to_owned()).unwrap_err();
+    assert_eq!(error.to_string(), "1:30: Resolution failed 'undefined'");
+
+    let source = "(i:add 1\n    (i:div 1 0))";
+    let error = intp.execute_source(source.to_owned()).unwrap_err();
+    assert_eq!(error.to_string(), "2:5: Division by zero");
+
+    let error = intp.execute_source("undefined".to_owned()).unwrap_err();
+    assert_eq!(error.to_string(), "1:1: Resolution failed 'undefined'");
+}
+
+#[test]
+fn test_int_errors() {
+    let mut intp = Interpreter::new().init();
+    let overflow = intp.execute_source("(i:add 9223372036854775807 1)".to_owned());
+    match overflow.unwrap_err().root() {
+        Error::IntegerOverflow(name) => assert_eq!(name, "i:add"),
+        other => panic!("Unexpected error: {}", other),
+    }
+    let division = intp.execute_source("(i:rem 1 0)".to_owned());
+    match division.unwrap_err().root() {
+        Error::DivisionByZero => {},
+        other => panic!("Unexpected error: {}", other),
+    }
+    let literal = intp.execute_source("99999999999999999999".to_owned());
+    match literal.unwrap_err().root() {
+        Error::ParseError(_) => {},
+        other => panic!("Unexpected error: {}", other),
+    }
+}
+
+#[test]
+fn test_string_literals() {
+    let mut intp = Interpreter::new().init();
+    intp.execute_source(r#"(set (quote s) "a \"quoted\"\n\\ \u{1F600}")"#.to_owned())
+        .expect("Error");
+    let value = intp.resolve("s").expect("Error");
+    assert_eq!(value, Value::Str("a \"quoted\"\n\\ \u{1F600}".to_owned()));
+    assert_eq!(value.format(), r#""a \"quoted\"\n\\ 😀""#);
+
+    for source in &[r#""unterminated"#, r#""\q""#, r#""\u{110000}""#, r#""\u{}""#] {
+        match intp.execute_source((*source).to_owned()).unwrap_err().root() {
+            Error::ParseError(_) => {},
+            other => panic!("Unexpected error: {}", other),
+        }
+    }
+}
+
+#[test]
+fn test_quote_shorthand() {
+    let parse = |source: &str| {
+        let tokens = parser::split_tokens(source.to_owned()).expect("Error");
+        Value::parse(tokens).expect("Error")
+    };
+    assert_eq!(parse("'(a 'b)"), parse("(quote (a (quote b)))"));
+    assert_eq!(parse("'(a 'b)").format(), "'(:a ':b)");
+    assert_eq!(parse(&parse("''x").format().replace(':', "")), parse("''x"));
+
+    let tokens = parser::split_tokens("(set 'a '".to_owned()).expect("Error");
+    assert!(parser::is_incomplete(&tokens));
+    let tokens = parser::split_tokens("'".to_owned()).expect("Error");
+    assert!(parser::is_incomplete(&tokens));
+    match parser::take_expr(tokens).unwrap_err().root() {
+        Error::ParseError(_) => {},
+        other => panic!("Unexpected error: {}", other),
+    }
+}
+
+#[test]
+fn test_deep_nesting() {
+    // Nesting doesn't use the native stack while parsing, even on a thread with a small one
+    thread::spawn(|| {
+        let depth = parser::MAX_NESTING;
+        let nested = format!("{}a{}", "(".repeat(depth), ")".repeat(depth));
+        let quoted = format!("{}a", "'".repeat(depth));
+        for source in &[nested, quoted] {
+            let tokens = parser::split_tokens(source.clone()).expect("Error");
+            let (expr, rest) = parser::take_expr(tokens).expect("Error");
+            assert!(rest.is_empty());
+            Value::parse(expr).expect("Error");
+        }
+
+        let mut intp = Interpreter::new().init();
+        let nested = format!("{}a{}", "(".repeat(depth - 2), ")".repeat(depth - 2));
+        intp.execute_source(format!("(set 'v (quote {}))", nested))
+            .expect("Error");
+        let formatted = intp.resolve("v").expect("Error").format();
+        assert_eq!(formatted, format!("'{}:a{}", "(".repeat(depth - 2), ")".repeat(depth - 2)));
+
+        // Deeper nesting is an error instead of a stack overflow
+        let nested = format!("{}a{}", "(".repeat(100_000), ")".repeat(100_000));
+        match intp.execute_source(nested).unwrap_err().root() {
+            Error::ParseError(_) => {},
+            other => panic!("Unexpected error: {}", other),
+        }
+
+        // Parsing uses the configured limit
+        let mut intp = Interpreter::new().max_nesting(10).init();
+        let nested = format!("{}a{}", "(".repeat(11), ")".repeat(11));
+        match intp.execute_source(nested).unwrap_err().root() {
+            Error::ParseError(_) => {},
+            other => panic!("Unexpected error: {}", other),
+        }
+        let tokens = parser::split_tokens(format!("{}a", "'".repeat(11))).expect("Error");
+        let (expr, _) = parser::take_expr(tokens).expect("Error");
+        assert!(intp.parse(expr).is_err());
+
+        let depth = parser::MAX_NESTING + 10;
+        let mut intp = Interpreter::new().max_nesting(depth).init();
+        let quoted = format!("{}a", "'".repeat(depth - 1));
+        intp.execute_source(format!("(set 'v {})", quoted)).expect("Error");
+    })
+    .join()
+    .unwrap();
+}
+
+#[test]
+fn test_tail_calls() {
+    let mut intp = Interpreter::new().init();
+    intp.execute_source(
+        "
+        (set 'repeat
+            (lambda 'n 'list 'acc
+                '(branch (eq? n 0)
+                    'acc
+                    '(repeat (i:sub n 1) list (q:concat acc list))
+                )
+            )
+        )
+        (set 'list (repeat 10 '(0 0 0 0 0 0 0 0 0 0) '()))
+        (set 'list (repeat 10 list '()))
+        (set 'list (repeat 10 list '()))
+        (set 'list (repeat 10 list '()))
+        (set 'length (q:count list))
+        "
+        .to_owned(),
+    )
+    .expect("Error");
+    assert_eq!(intp.resolve("length").expect("Error"), Value::Int(100_000));
+
+    // A Peano length nests a quote per item, so `q:length` is only tail recursive up to the
+    // nesting limit, far deeper than the recursion limit though
+    let source = "(set 'length (p:to_int (q:length (repeat 90 '(0 0 0 0 0 0 0 0 0 0) '()))))";
+    intp.execute_source(source.to_owned()).expect("Error");
+    assert_eq!(intp.resolve("length").expect("Error"), Value::Int(900));
+}
+
+#[test]
+fn test_recursion_limit() {
+    let mut intp = Interpreter::new().max_depth(100).init();
+    let source = "(set 'runaway (lambda 'n '(i:add 1 (runaway n)))) (runaway 0)";
+    let result = intp.execute_source(source.to_owned());
+    let error = result.unwrap_err();
+    match error.root() {
+        Error::RecursionLimit { depth, .. } => assert_eq!(*depth, 100),
+        other => panic!("Unexpected error: {}", other),
+    }
+
+    // Recursive frames are collapsed in the trace, which shows where each call was made
+    let trace = error.trace_lines();
+    assert_eq!(trace.len(), 2);
+    assert_eq!(trace[0], "in runaway at 1:51");
+    assert!(trace[1].starts_with("in runaway at 1:36 (×"), "{:?}", trace);
+
+    // The interpreter is still usable after the error
+    intp.execute_source("(assert (eq? 3 (i:add 1 2)))".to_owned())
+        .expect("Error");
+}
+
+#[test]
+fn test_default_recursion_limit_on_thread() {
+    // Spawned threads have a smaller stack than the main thread
+    let result = thread::spawn(|| {
+        let mut intp = Interpreter::new().init();
+        let source = "(set 'runaway (lambda 'n '(i:add 1 (runaway n)))) (runaway 0)";
+        intp.execute_source(source.to_owned())
+    })
+    .join()
+    .unwrap();
+    match result.unwrap_err().root() {
+        Error::RecursionLimit { .. } => {},
+        other => panic!("Unexpected error: {}", other),
+    }
+}
+
+#[test]
+fn test_nesting_limit() {
+    // Deep values are cloned and dropped recursively, so their nesting is limited like evaluation
+    let result = thread::spawn(|| {
+        let mut intp = Interpreter::new().init();
+        intp.execute_source("(p:to_int (p:from_int 20000))".to_owned())
+    })
+    .join()
+    .unwrap();
+    match result.unwrap_err().root() {
+        Error::NestingLimit { depth } => assert_eq!(*depth, 1001),
+        other => panic!("Unexpected error: {}", other),
+    }
+
+    // Values nesting through expressions are limited too, and so the interpreter holding them
+    // can be dropped, and they can be printed
+    thread::spawn(|| {
+        let mut intp = Interpreter::new().init();
+        let wrap = "(set 'wrap (lambda 'n 'v '(branch (eq? n 0) 'v '(wrap (i:sub n 1) '(v)))))";
+        intp.execute_source(wrap.to_owned()).expect("Error");
+        for source in &["(set 'x (wrap 100000 'a))", "(println (wrap 100000 'a))"] {
+            match intp.execute_source((*source).to_owned()).unwrap_err().root() {
+                Error::NestingLimit { depth } => assert_eq!(*depth, 1001),
+                other => panic!("Unexpected error: {}", other),
+            }
+        }
+        let output = OutputBuffer::new();
+        intp.set_output(output.clone());
+        intp.execute_source("(set 'x (wrap 400 'a)) (println x)".to_owned())
+            .expect("Error");
+        assert!(output.take().ends_with(&format!("{}\n", ")".repeat(400))));
+        let _ = intp.clone();
+
+        // So are values nesting through the bindings closures capture
+        let grow = "(set 'grow (lambda 'n '(branch (eq? n 0) '() \
+                    '(block '(set 'c (closure '_ '(c))) '(grow (i:sub n 1))))))";
+        intp.execute_source(grow.to_owned()).expect("Error");
+        intp.execute_source("(set 'c ())".to_owned()).expect("Error");
+        match intp.execute_source("(grow 100000)".to_owned()).unwrap_err().root() {
+            Error::NestingLimit { .. } => {},
+            other => panic!("Unexpected error: {}", other),
+        }
+    })
+    .join()
+    .unwrap();
+
+    let mut intp = Interpreter::new().max_nesting(20).init();
+    intp.execute_source("(set 'x (p:from_int 16))".to_owned())
+        .expect("Error");
+    let error = intp.execute_source("(p:inc (p:inc (p:inc (p:inc x))))".to_owned()).unwrap_err();
+    assert_eq!(error.to_string(), "<stdlib>/peano.mls:39:10: Nesting limit exceeded at depth 21");
+}
+
+#[test]
+fn test_fuel() {
+    let mut intp = Interpreter::new().init();
+    intp.execute_source("(set 'forever '(unquote forever))".to_owned())
+        .expect("Error");
+
+    intp.set_fuel(Some(10_000));
+    match intp
+        .execute_source("(unquote forever)".to_owned())
+        .unwrap_err()
+        .root()
+    {
+        Error::OutOfFuel { .. } => {},
+        other => panic!("Unexpected error: {}", other),
+    }
+    assert_eq!(intp.fuel(), Some(0));
+
+    intp.set_fuel(Some(10_000));
+    intp.reset_steps();
+    intp.execute_source("(p:mul p:2 p:3)".to_owned()).expect("Error");
+    let steps = intp.steps();
+    assert!(steps > 0);
+    assert_eq!(intp.fuel(), Some(10_000 - steps));
+}
+
+#[test]
+fn test_cancellation() {
+    let mut intp = Interpreter::new().init();
+    intp.execute_source("(set 'forever '(unquote forever))".to_owned())
+        .expect("Error");
+
+    let token: CancelToken = intp.cancel_token();
+    let canceller = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        token.cancel();
+    });
+    match intp
+        .execute_source("(unquote forever)".to_owned())
+        .unwrap_err()
+        .root()
+    {
+        Error::Cancelled => {},
+        other => panic!("Unexpected error: {}", other),
+    }
+    canceller.join().unwrap();
+    assert!(!intp.cancel_token().is_cancelled());
+
+    intp.set_deadline(Some(Instant::now() + Duration::from_millis(50)));
+    match intp
+        .execute_source("(unquote forever)".to_owned())
+        .unwrap_err()
+        .root()
+    {
+        Error::DeadlineExceeded => {},
+        other => panic!("Unexpected error: {}", other),
+    }
+    intp.set_deadline(None);
+
+    // Bindings are intact and evaluation works after an interruption
+    intp.execute_source("(set 'sum (p:to_int (p:add p:1 p:2)))".to_owned())
+        .expect("Error");
+    assert_eq!(intp.resolve("sum").expect("Error"), Value::Int(3));
+
+    // A request made while no run is in progress doesn't stop the next one, at any entry point
+    let tokens = parser::split_tokens("(i:add 1 2)".to_owned()).expect("Error");
+    let value = Value::parse(tokens).expect("Error");
+    intp.cancel_token().cancel();
+    assert!(!intp.cancel_token().is_cancelled());
+    assert_eq!(intp.execute(value).expect("Error"), Value::Int(3));
+
+    let mut intp = Interpreter::new().module_dir("tests/modules").init();
+    intp.cancel_token().cancel();
+    intp.execute_source("1".to_owned()).expect("Error");
+    intp.cancel_token().cancel();
+    intp.import("private").expect("Error");
+
+    let intp = Interpreter::new();
+    intp.cancel_token().cancel();
+    intp.try_init().expect("Error");
+}
+
+#[test]
+fn test_cancellation_of_clones() {
+    let mut first = Interpreter::new().init();
+    first
+        .execute_source("(set 'forever '(unquote forever))".to_owned())
+        .expect("Error");
+    let mut second = first.clone();
+
+    // Cancelling one copy doesn't stop a run of another
+    first.set_deadline(Some(Instant::now() + Duration::from_millis(200)));
+    let (done, finished) = mpsc::channel();
+    let running = thread::spawn(move || {
+        let result = first.execute_source("(unquote forever)".to_owned());
+        done.send(()).unwrap();
+        result
+    });
+    while finished.try_recv().is_err() {
+        second.cancel_token().cancel();
+    }
+    match running.join().unwrap().unwrap_err().root() {
+        Error::DeadlineExceeded => {},
+        other => panic!("Unexpected error: {}", other),
+    }
+
+    // And each copy can still be cancelled through its own token
+    let token = second.cancel_token();
+    let canceller = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        token.cancel();
+    });
+    match second
+        .execute_source("(unquote forever)".to_owned())
+        .unwrap_err()
+        .root()
+    {
+        Error::Cancelled => {},
+        other => panic!("Unexpected error: {}", other),
+    }
+    canceller.join().unwrap();
+}
+
+#[test]
+fn test_empty_expressions() {
+    // Parsing makes Unit of `()`, but embedders can make empty expressions, which work like it
+    let mut intp = Interpreter::new().init();
+    let empty = Value::Expr(List::from(Vec::new()));
+    assert_eq!(intp.execute(empty.clone()).expect("Error"), Value::Unit);
+
+    intp.bind("empty".to_owned(), Value::Quot(Box::new(empty), None));
+    intp.execute_source("(assert (q:empty? empty)) (assert (q:empty? (q:tail empty)))".to_owned())
+        .expect("Error");
+    match intp.execute_source("(q:head empty)".to_owned()).unwrap_err().root() {
+        Error::OutOfRange(message) => assert_eq!(message, "Cannot get first element of an empty list"),
+        other => panic!("Unexpected error: {}", other),
+    }
+}
+
+#[test]
+fn test_values_and_interpreters_are_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<Value>();
+    assert_send::<List>();
+    assert_send::<Interpreter>();
+    assert_send::<OutputBuffer>();
+
+    let mut intp = Interpreter::new().init();
+    intp.execute_source("(set 'v '(a (b c)))".to_owned())
+        .expect("Error");
+    let value = intp.resolve("v").expect("Error");
+    let formatted = thread::spawn(move || value.format()).join().unwrap();
+    assert_eq!(formatted, "'(:a (:b :c))");
+}
+
+#[test]
+fn test_output_capture() {
+    let mut intp = Interpreter::new().init();
+    let buffer = OutputBuffer::new();
+    intp.set_output(buffer.clone());
+    intp.execute_source(r#"(println "count:" 3) (println 'done)"#.to_owned())
+        .expect("Error");
+    assert_eq!(buffer.take(), "count: 3\n':done\n");
+    assert_eq!(buffer.contents(), "");
+
+    // The interpreter can run on another thread, with output captured on this one
+    thread::spawn(move || intp.execute_source("(println 'moved)".to_owned()).expect("Error"))
+        .join()
+        .unwrap();
+    assert_eq!(buffer.contents(), "':moved\n");
+}
+
+#[test]
+fn test_deftest() {
+    let mut intp = Interpreter::new().init();
+    assert!(intp.resolve("deftest").is_err());
+    intp.import("testing").expect("Error");
+    intp.execute_file("tests/deftest/arith_test.mls").expect("Error");
+    let mut names: Vec<&String> = intp
+        .namespace()
+        .keys()
+        .filter(|n| n.starts_with("test:"))
+        .collect();
+    names.sort();
+    assert_eq!(names, [
+        "test:double_int",
+        "test:double_is_unchanged",
+        "test:double_peano",
+        "test:rebinding_is_isolated"
+    ]);
+
+    // Tests only run when asked to
+    intp.execute_source("(deftest 'failing '(assert false))".to_owned())
+        .expect("Error");
+    intp.execute_source("(unquote test:double_int)".to_owned())
+        .expect("Error");
+    match intp
+        .execute_source("(unquote test:failing)".to_owned())
+        .unwrap_err()
+        .root()
+    {
+        Error::AssertionFailed(_) => {},
+        other => panic!("Unexpected error: {}", other),
+    }
+}
+
+/// Fresh directory under the system temp directory, with the given files written in it
+fn temp_tree(name: &str, files: &[(&str, &str)]) -> io::Result<PathBuf> {
+    let root = env::temp_dir().join(format!("minilisp-{}-{}", name, process::id()));
+    if root.exists() {
+        fs::remove_dir_all(&root)?;
+    }
+    for (path, contents) in files {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, contents)?;
+    }
+    Ok(root)
+}
+
+#[test]
+fn test_runner() -> io::Result<()> {
+    let counter = "(set 'counter 0)
+        (deftest 'first '(block '(set 'counter (i:add counter 1)) '(assert (eq? counter 1))))
+        (deftest 'second '(block '(set 'counter (i:add counter 1)) '(assert (eq? counter 1))))";
+    let failing = "(deftest 'fails '(block '(println \"output\") '(assert (eq? 1 2))))
+        (deftest 'passes '(assert true))";
+    let skipped = "(deftest 'skipped '(assert false))";
+    let root = temp_tree("runner", &[
+        ("counter_test.mls", counter),
+        ("failing_test.mls", failing),
+        ("module.mls", "(export 'x)"),
+        ("nested/nested_test.mls", "(deftest 'nested '(assert true))"),
+        ("target/skipped_test.mls", skipped),
+        (".hidden/skipped_test.mls", skipped),
+    ])?;
+
+    // Only test files are found, and not under target or hidden directories
+    let files = testing::find_test_files(&[&root])?;
+    assert_eq!(files, [
+        root.join("counter_test.mls"),
+        root.join("failing_test.mls"),
+        root.join("nested").join("nested_test.mls"),
+    ]);
+
+    // Each test runs in a fresh interpreter, so both see the counter start from 0
+    let mut report = Vec::new();
+    let summary = testing::run_tests(&files, || Interpreter::new().try_init(), &mut report)?;
+    let report = String::from_utf8(report).unwrap();
+    assert_eq!(summary.passed, 4);
+    assert_eq!(summary.failed, [format!(
+        "{}::fails",
+        root.join("failing_test.mls").display()
+    )]);
+    assert!(report.contains("output\nError: "), "{}", report);
+    assert!(
+        report.ends_with("test result: FAILED. 4 passed; 1 failed\n"),
+        "{}",
+        report
+    );
+
+    // The test command exits with an error if any test failed
+    let status = |path: &Path| {
+        Command::new(env!("CARGO_BIN_EXE_minilisp"))
+            .arg("test")
+            .arg(path)
+            .output()
+            .map(|output| output.status.code())
+    };
+    assert_eq!(status(&root)?, Some(1));
+    assert_eq!(status(&root.join("counter_test.mls"))?, Some(0));
+
+    fs::remove_dir_all(&root)
+}
+
+#[test]
+fn test_cli_limits() -> io::Result<()> {
+    // The command line interpreter runs on a big stack, and allows deeper evaluation and values
+    let source = "(set 'ones (lambda 'n 'l '(branch (eq? n 0) 'l '(ones (i:sub n 1) (q:concat '(1) l)))))
+        (set 'len (lambda 'l '(branch (q:empty? l) '0 '(i:add 1 (len (q:tail l))))))
+        (println (len (ones 1000 '())))
+        (println (p:to_int (p:from_int 2000)))";
+    let root = temp_tree("cli", &[("deep.mls", source)])?;
+    let output = Command::new(env!("CARGO_BIN_EXE_minilisp"))
+        .arg(root.join("deep.mls"))
+        .output()?;
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1000\n2000\n");
+    fs::remove_dir_all(&root)
+}